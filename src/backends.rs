@@ -3,7 +3,8 @@ use std::result;
 use crate::raqote_draw::RaqoteDrawBackend;
 #[cfg(feature = "skia")]
 use crate::skia_draw::SkiaBackend;
-use crate::ofd::{ImageObject, PathObject, PhysicalBox, TextObject};
+use crate::ofd::{ImageObject, PageBox, PhysicalBox};
+use crate::style::{Ctm, ResolvedClip, ResolvedPath, ResolvedText};
 
 pub type Result<T> = result::Result<T, DrawError>;
 
@@ -47,6 +48,15 @@ impl From<raqote::Transform> for Transform {
 //     }
 // }
 
+/// Options of drawing a document, given to the backend of every page.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RenderOptions {
+    /// Whether image objects are drawn with their `Substitution` image when they have one.
+    pub use_image_substitutions: bool,
+    /// The box of the page area pages are output clipped to.
+    pub page_box: PageBox,
+}
+
 pub trait DrawBackend {
     fn output_page(&mut self, out_f_name: &String) -> Result<()>;
 
@@ -54,22 +64,32 @@ pub trait DrawBackend {
     fn save(&mut self) -> Transform;
 
     fn scale(&mut self);
+    /// Maps what is drawn next through `transform`, until the drawing state is restored.
+    fn concat(&mut self, transform: &Ctm);
 
     fn restore(&mut self, transform: &Transform);
 
-    fn draw_path_object(&mut self, draw_param_id: Option<&String>, path_object: &PathObject);
-    fn draw_text_object(&mut self, draw_param_id: Option<&String>, text_object: &TextObject);
-    fn draw_image_object(&mut self, image_object: &ImageObject);
+    fn draw_path(&mut self, path: &ResolvedPath);
+    fn draw_text(&mut self, text: &ResolvedText);
+    /// Draws the image into the unit square, placed by the current transform. Stencils are
+    /// painted with the fill colour of the draw param.
+    fn draw_image_object(&mut self, image_object: &ImageObject, layer_draw_param_id: Option<&String>);
+    /// Draws everything until `end_group` into a group, composited with `alpha` as a whole.
+    fn begin_group(&mut self, alpha: u8);
+    fn end_group(&mut self);
+    /// Intersects the clip with `clip`, until the matching `pop_clip`.
+    fn push_clip(&mut self, clip: &ResolvedClip);
+    fn pop_clip(&mut self);
 }
 
-pub fn new_draw_backend(width: i32, height: i32) -> Box<dyn DrawBackend> {
+pub fn new_draw_backend(width: i32, height: i32, options: RenderOptions) -> Box<dyn DrawBackend> {
     #[cfg(feature = "skia")]
     {
-        Box::new(SkiaBackend::new(width, height))
+        Box::new(SkiaBackend::new(width, height, options))
     }
     #[cfg(feature = "raqote")]
     {
-        Box::new(RaqoteDrawBackend::new(width, height))
+        Box::new(RaqoteDrawBackend::new(width, height, options))
     }
 }
 
@@ -1,4 +1,4 @@
-use image_demo::ofd::OFDFile;
+use image_demo::ofd::{OFDFile, PageBox};
 
 #[allow(dead_code)]
 fn indent(size: usize) -> String {
@@ -14,20 +14,44 @@ fn print_type_of<T>(_: &T) {
 }
 
 fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
     std::process::exit(real_main());
 }
 
+fn parse_page_box(name: &str) -> Option<PageBox> {
+    match name {
+        "physical" => Some(PageBox::Physical),
+        "application" => Some(PageBox::Application),
+        "content" => Some(PageBox::Content),
+        "bleed" => Some(PageBox::Bleed),
+        _ => None,
+    }
+}
+
 fn real_main() -> i32 {
     let args: Vec<_> = std::env::args().collect();
     if args.len() < 2 {
-        println!("Usage: {} <filename.ofd> ...", args[0]);
+        println!("Usage: {} [--page-box=physical|application|content|bleed] <filename.ofd> ...", args[0]);
         return 1;
     }
+    let mut page_box = PageBox::Physical;
+    for arg in &args[1..] {
+        if let Some(name) = arg.strip_prefix("--page-box=") {
+            match parse_page_box(name) {
+                Some(b) => page_box = b,
+                None => {
+                    println!("Unknown page box: {}", name);
+                    return 1;
+                }
+            }
+        }
+    }
     args.iter().skip_while(|arg| {
         !arg.ends_with(".ofd")
-    }).for_each(|arg| {
+    }).filter(|arg| !arg.starts_with("--")).for_each(|arg| {
         println!("Input ofd: {}", arg);
         let mut ofd_file = OFDFile::new(arg);
+        ofd_file.use_page_box(page_box);
         ofd_file.draw();
     });
     0
@@ -0,0 +1,233 @@
+//! Decoding of CCITT Group 4 (T.6) fax images, the bilevel compression of scanned TIFFs.
+//!
+//! Every row is coded against the row above it, the changing elements: pixels whose
+//! colour differs from the one on their left. Rows start white, above the first one is
+//! an imaginary white row.
+
+use std::collections::HashMap;
+
+use image::{GrayImage, Luma};
+use lazy_static::lazy_static;
+
+/// Codes of white runs 0 to 63.
+const WHITE_TERMINATING: [&str; 64] = [
+    "00110101", "000111", "0111", "1000", "1011", "1100", "1110", "1111",
+    "10011", "10100", "00111", "01000", "001000", "000011", "110100", "110101",
+    "101010", "101011", "0100111", "0001100", "0001000", "0010111", "0000011", "0000100",
+    "0101000", "0101011", "0010011", "0100100", "0011000", "00000010", "00000011", "00011010",
+    "00011011", "00010010", "00010011", "00010100", "00010101", "00010110", "00010111", "00101000",
+    "00101001", "00101010", "00101011", "00101100", "00101101", "00000100", "00000101", "00001010",
+    "00001011", "01010010", "01010011", "01010100", "01010101", "00100100", "00100101", "01011000",
+    "01011001", "01011010", "01011011", "01001010", "01001011", "00110010", "00110011", "00110100",
+];
+/// Codes of white runs 64 to 1728, in steps of 64.
+const WHITE_MAKEUP: [&str; 27] = [
+    "11011", "10010", "010111", "0110111", "00110110", "00110111", "01100100", "01100101",
+    "01101000", "01100111", "011001100", "011001101", "011010010", "011010011", "011010100", "011010101",
+    "011010110", "011010111", "011011000", "011011001", "011011010", "011011011", "010011000", "010011001",
+    "010011010", "011000", "010011011",
+];
+/// Codes of black runs 0 to 63.
+const BLACK_TERMINATING: [&str; 64] = [
+    "0000110111", "010", "11", "10", "011", "0011",
+    "0010", "00011", "000101", "000100", "0000100", "0000101",
+    "0000111", "00000100", "00000111", "000011000", "0000010111", "0000011000",
+    "0000001000", "00001100111", "00001101000", "00001101100", "00000110111", "00000101000",
+    "00000010111", "00000011000", "000011001010", "000011001011", "000011001100", "000011001101",
+    "000001101000", "000001101001", "000001101010", "000001101011", "000011010010", "000011010011",
+    "000011010100", "000011010101", "000011010110", "000011010111", "000001101100", "000001101101",
+    "000011011010", "000011011011", "000001010100", "000001010101", "000001010110", "000001010111",
+    "000001100100", "000001100101", "000001010010", "000001010011", "000000100100", "000000110111",
+    "000000111000", "000000100111", "000000101000", "000001011000", "000001011001", "000000101011",
+    "000000101100", "000001011010", "000001100110", "000001100111",
+];
+/// Codes of black runs 64 to 1728, in steps of 64.
+const BLACK_MAKEUP: [&str; 27] = [
+    "0000001111", "000011001000", "000011001001", "000001011011", "000000110011", "000000110100",
+    "000000110101", "0000001101100", "0000001101101", "0000001001010", "0000001001011", "0000001001100",
+    "0000001001101", "0000001110010", "0000001110011", "0000001110100", "0000001110101", "0000001110110",
+    "0000001110111", "0000001010010", "0000001010011", "0000001010100", "0000001010101", "0000001011010",
+    "0000001011011", "0000001100100", "0000001100101",
+];
+/// Codes of runs 1792 to 2560 of either colour, in steps of 64.
+const EXTENDED_MAKEUP: [&str; 13] = [
+    "00000001000", "00000001100", "00000001101", "000000010010", "000000010011", "000000010100",
+    "000000010101", "000000010110", "000000010111", "000000011100", "000000011101", "000000011110",
+    "000000011111",
+];
+
+lazy_static! {
+    /// Run lengths by code length and code.
+    static ref WHITE_RUNS: HashMap<(u8, u16), u32> = run_codes(&WHITE_TERMINATING, &WHITE_MAKEUP);
+    static ref BLACK_RUNS: HashMap<(u8, u16), u32> = run_codes(&BLACK_TERMINATING, &BLACK_MAKEUP);
+}
+
+fn run_codes(terminating: &[&str], makeup: &[&str]) -> HashMap<(u8, u16), u32> {
+    let runs = (0..64).chain((1..=40).map(|i| i * 64));
+    terminating.iter().chain(makeup).chain(&EXTENDED_MAKEUP)
+        .zip(runs)
+        .map(|(code, run)| ((code.len() as u8, u16::from_str_radix(code, 2).unwrap()), run))
+        .collect()
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// `FillOrder` 2, the first pixel is in the lowest bit of a byte.
+    lsb_first: bool,
+}
+
+impl BitReader<'_> {
+    fn bit(&mut self) -> Result<u16, String> {
+        let byte = *self.data.get(self.pos / 8).ok_or("G4 data ends early")?;
+        let shift = if self.lsb_first { self.pos % 8 } else { 7 - self.pos % 8 };
+        self.pos += 1;
+        Ok((byte >> shift) as u16 & 1)
+    }
+
+    fn mode(&mut self) -> Result<Mode, String> {
+        let mut code = 0;
+        for len in 1..=12 {
+            code = code << 1 | self.bit()?;
+            return Ok(match (len, code) {
+                (1, 0b1) => Mode::Vertical(0),
+                (3, 0b011) => Mode::Vertical(1),
+                (3, 0b010) => Mode::Vertical(-1),
+                (3, 0b001) => Mode::Horizontal,
+                (4, 0b0001) => Mode::Pass,
+                (6, 0b000011) => Mode::Vertical(2),
+                (6, 0b000010) => Mode::Vertical(-2),
+                (7, 0b0000011) => Mode::Vertical(3),
+                (7, 0b0000010) => Mode::Vertical(-3),
+                (12, 0b000000000001) => Mode::EndOfBlock,
+                _ => continue,
+            });
+        }
+        Err(format!("unsupported G4 code {:012b}", code))
+    }
+
+    /// A run of makeup codes and a terminating code.
+    fn run(&mut self, black: bool) -> Result<u32, String> {
+        let runs = if black { &*BLACK_RUNS } else { &*WHITE_RUNS };
+        let mut total = 0;
+        loop {
+            let (mut code, mut len) = (0, 0);
+            let run = loop {
+                code = code << 1 | self.bit()?;
+                len += 1;
+                if let Some(run) = runs.get(&(len, code)) {
+                    break *run;
+                }
+                if len > 13 {
+                    return Err(format!("invalid G4 run code {:b}", code));
+                }
+            };
+            total += run;
+            if run < 64 {
+                return Ok(total);
+            }
+        }
+    }
+}
+
+enum Mode {
+    Pass,
+    Horizontal,
+    /// The next changing element is this far right of the one above.
+    Vertical(i64),
+    EndOfBlock,
+}
+
+/// Decodes a G4 strip of `width` by `height` pixels, black `0` and white `255`. Rows
+/// after an end of block stay white.
+pub fn decode_g4(data: &[u8], width: u32, height: u32, lsb_first: bool) -> Result<GrayImage, String> {
+    let mut image = GrayImage::from_pixel(width, height, Luma([255]));
+    let mut bits = BitReader { data, pos: 0, lsb_first };
+    let width = width as i64;
+    let mut reference: Vec<i64> = Vec::new();
+    for y in 0..height {
+        let mut coding = Vec::new();
+        let mut paint = |from: i64, to: i64, black: bool| {
+            if black {
+                for x in from.max(0)..to.min(width) {
+                    image.put_pixel(x as u32, y, Luma([0]));
+                }
+            }
+        };
+        let mut a0 = -1;
+        let mut black = false;
+        while a0 < width {
+            // the first changing element above, right of a0, to the colour opposite of a0's
+            let b = reference.iter().enumerate()
+                .position(|(i, &t)| t > a0 && (i % 2 == 0) != black);
+            let b1 = b.map_or(width, |i| reference[i]);
+            let b2 = b.and_then(|i| reference.get(i + 1).copied()).unwrap_or(width);
+            let start = a0.max(0);
+            match bits.mode()? {
+                Mode::Pass => {
+                    paint(start, b2, black);
+                    a0 = b2;
+                }
+                Mode::Horizontal => {
+                    let a1 = (start + bits.run(black)? as i64).min(width);
+                    let a2 = (a1 + bits.run(!black)? as i64).min(width);
+                    paint(start, a1, black);
+                    paint(a1, a2, !black);
+                    coding.extend([a1, a2]);
+                    a0 = a2;
+                }
+                Mode::Vertical(delta) => {
+                    let a1 = b1 + delta;
+                    if a1 < start || a1 > width {
+                        return Err(format!("G4 changing element {} out of row {}", a1, y));
+                    }
+                    paint(start, a1, black);
+                    coding.push(a1);
+                    a0 = a1;
+                    black = !black;
+                }
+                Mode::EndOfBlock => return Ok(image),
+            }
+        }
+        reference = coding;
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode_g4;
+
+    fn pack(bits: &str) -> Vec<u8> {
+        let bits: Vec<u8> = bits.bytes().filter(|b| !b.is_ascii_whitespace()).map(|b| b - b'0').collect();
+        bits.chunks(8)
+            .map(|c| c.iter().enumerate().fold(0, |byte, (i, bit)| byte | bit << (7 - i)))
+            .collect()
+    }
+
+    fn row(image: &image::GrayImage, y: u32) -> String {
+        (0..image.width()).map(|x| if image.get_pixel(x, y)[0] == 0 { '#' } else { '.' }).collect()
+    }
+
+    #[test]
+    fn test_decode_g4() {
+        // horizontal: 2 white, 3 black; V0 to the end; then V0 three times to repeat it;
+        // the end of block leaves the last row white
+        let data = pack("001 0111 10 1  111  000000000001 000000000001");
+        let image = decode_g4(&data, 8, 3, false).unwrap();
+        assert_eq!(row(&image, 0), "..###...");
+        assert_eq!(row(&image, 1), "..###...");
+        assert_eq!(row(&image, 2), "........");
+
+        let reversed: Vec<u8> = data.iter().map(|b| b.reverse_bits()).collect();
+        assert_eq!(decode_g4(&reversed, 8, 3, true).unwrap(), image);
+
+        // makeup codes: no white, 100 black; then V0, VL1 and a pass to the end
+        let data = pack("001 00110101 0000001111 000011010100  1 010 0001");
+        let image = decode_g4(&data, 100, 2, false).unwrap();
+        assert_eq!(row(&image, 0), "#".repeat(100));
+        assert_eq!(row(&image, 1), "#".repeat(99) + ".");
+
+        assert!(decode_g4(&pack("001"), 8, 1, false).is_err());
+    }
+}
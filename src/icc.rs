@@ -0,0 +1,189 @@
+//! ICC profile based colour management.
+//!
+//! `ColorSpace` resources referencing a `Profile` get a transform to sRGB, used for
+//! their colours and as the default profile of images without an embedded one.
+
+use std::sync::{Arc, Mutex};
+
+use image::RgbImage;
+use jpeg_decoder::PixelFormat;
+use lazy_static::lazy_static;
+use qcms::{DataType, Intent, Profile, Transform};
+
+use crate::node_draw::ScopedMap;
+use crate::ofd::ColorSpaceType;
+
+lazy_static! {
+    pub(crate) static ref MUTEX_ICC_PROFILES: Mutex<ScopedMap<String, Arc<IccProfile>>> = Mutex::new(ScopedMap::new());
+}
+
+/// An ICC profile with its transform to 8 bit sRGB.
+pub struct IccProfile {
+    pub type_: ColorSpaceType,
+    transform: Transform,
+}
+
+impl IccProfile {
+    pub fn new(type_: ColorSpaceType, data: Vec<u8>) -> Option<Self> {
+        let transform = srgb_transform(&data, data_type(type_))?;
+        Some(IccProfile { type_, transform })
+    }
+
+    /// Converts packed 8 bit pixels of the profile's colour space to packed RGB.
+    pub fn to_srgb(&self, pixels: &[u8]) -> Vec<u8> {
+        let channels = self.type_.channels();
+        let mut rgb = vec![0; pixels.len() / channels * 3];
+        self.transform.convert(&pixels[..rgb.len() / 3 * channels], &mut rgb);
+        rgb
+    }
+}
+
+impl ColorSpaceType {
+    pub fn channels(&self) -> usize {
+        match self {
+            ColorSpaceType::GRAY => 1,
+            ColorSpaceType::RGB => 3,
+            ColorSpaceType::CMYK => 4,
+        }
+    }
+}
+
+fn data_type(type_: ColorSpaceType) -> DataType {
+    match type_ {
+        ColorSpaceType::GRAY => DataType::Gray8,
+        ColorSpaceType::RGB => DataType::RGB8,
+        ColorSpaceType::CMYK => DataType::CMYK,
+    }
+}
+
+fn srgb_transform(profile: &[u8], data_type: DataType) -> Option<Transform> {
+    let profile = Profile::new_from_slice(profile, false)?;
+    Transform::new_to(&profile, &Profile::new_sRGB(), data_type, DataType::RGB8, Intent::default())
+}
+
+/// Registers the ICC profile of a `ColorSpace` resource, `false` if it can't be used
+/// for the colour space type.
+pub fn register_profile(color_space_id: &str, type_: ColorSpaceType, data: Vec<u8>) -> bool {
+    match IccProfile::new(type_, data) {
+        Some(profile) => {
+            MUTEX_ICC_PROFILES.lock().unwrap().insert(color_space_id.to_string(), Arc::new(profile));
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn get_profile(color_space_id: &str) -> Option<Arc<IccProfile>> {
+    MUTEX_ICC_PROFILES.lock().unwrap().get(color_space_id).cloned()
+}
+
+/// Converts normalized (0.0 - 1.0) channels through the profile of a colour space,
+/// `None` if it has no usable profile.
+pub fn icc_color(color_space_id: &str, components: &[f32]) -> Option<(u8, u8, u8)> {
+    let profile = get_profile(color_space_id)?;
+    if components.len() < profile.type_.channels() {
+        return None;
+    }
+    let pixel: Vec<u8> = components[..profile.type_.channels()].iter()
+        .map(|v| (v.clamp(0., 1.) * 255.).round() as u8)
+        .collect();
+    let rgb = profile.to_srgb(&pixel);
+    Some((rgb[0], rgb[1], rgb[2]))
+}
+
+/// Decodes a JPEG through its embedded ICC profile, or through `default_profile` when
+/// it has none and the colour spaces match. `None` if the image isn't colour managed
+/// this way, so it is decoded as usual; only the header is read to find that out.
+pub fn decode_jpeg(buf: &[u8], default_profile: Option<&IccProfile>) -> Option<RgbImage> {
+    let mut decoder = jpeg_decoder::Decoder::new(buf);
+    decoder.read_info().ok()?;
+    let info = decoder.info()?;
+    let type_ = match info.pixel_format {
+        PixelFormat::L8 => ColorSpaceType::GRAY,
+        PixelFormat::RGB24 => ColorSpaceType::RGB,
+        // jpeg-decoder undoes the Adobe inversion, the samples are ink amounts
+        PixelFormat::CMYK32 => ColorSpaceType::CMYK,
+        PixelFormat::L16 => return None,
+    };
+
+    // the embedded profile, else the default one
+    let transform = match decoder.icc_profile() {
+        Some(data) => {
+            let transform = srgb_transform(&data, data_type(type_));
+            if transform.is_none() {
+                log::warn!("unsupported ICC profile embedded in JPEG");
+            }
+            Some(transform?)
+        }
+        None => match default_profile {
+            Some(profile) if profile.type_ == type_ && type_ != ColorSpaceType::RGB => None,
+            _ => return None,
+        },
+    };
+    let pixels = decoder.decode().ok()?;
+    let rgb = match transform {
+        Some(transform) => {
+            let mut rgb = vec![0; pixels.len() / type_.channels() * 3];
+            transform.convert(&pixels, &mut rgb);
+            rgb
+        }
+        None => default_profile?.to_srgb(&pixels),
+    };
+    RgbImage::from_raw(info.width as u32, info.height as u32, rgb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_jpeg, icc_color, register_profile, IccProfile};
+    use crate::node_draw::ResourceScope;
+    use crate::ofd::ColorSpaceType;
+    use image::{DynamicImage, GrayImage, ImageOutputFormat, Luma};
+    use std::io::Cursor;
+
+    /// A minimal display profile for linear (gamma 1.0) gray.
+    fn linear_gray_profile() -> Vec<u8> {
+        let mut data = vec![0u8; 160];
+        data[0..4].copy_from_slice(&160u32.to_be_bytes());
+        data[12..16].copy_from_slice(b"mntr");
+        data[16..20].copy_from_slice(b"GRAY");
+        data[20..24].copy_from_slice(b"XYZ ");
+        data[128..132].copy_from_slice(&1u32.to_be_bytes());
+        data[132..136].copy_from_slice(b"kTRC");
+        data[136..140].copy_from_slice(&144u32.to_be_bytes());
+        data[140..144].copy_from_slice(&14u32.to_be_bytes());
+        data[144..148].copy_from_slice(b"curv");
+        data[152..156].copy_from_slice(&1u32.to_be_bytes());
+        data[156..158].copy_from_slice(&0x0100u16.to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn test_icc_color() {
+        let _scope = ResourceScope::open();
+        assert!(!register_profile("test_bad_icc", ColorSpaceType::GRAY, b"not a profile".to_vec()));
+        assert_eq!(icc_color("test_bad_icc", &[0.5]), None);
+
+        assert!(register_profile("test_linear_gray_icc", ColorSpaceType::GRAY, linear_gray_profile()));
+        assert_eq!(icc_color("test_linear_gray_icc", &[0.]), Some((0, 0, 0)));
+        assert_eq!(icc_color("test_linear_gray_icc", &[1.]), Some((255, 255, 255)));
+        // linear mid gray is lighter once encoded as sRGB
+        let (r, g, b) = icc_color("test_linear_gray_icc", &[0.5]).unwrap();
+        assert!(r == g && g == b && (180..=195).contains(&r), "{}", r);
+        assert_eq!(icc_color("test_linear_gray_icc", &[]), None);
+    }
+
+    #[test]
+    fn test_decode_jpeg() {
+        let mut jpeg = Cursor::new(Vec::new());
+        DynamicImage::ImageLuma8(GrayImage::from_pixel(2, 2, Luma([128])))
+            .write_to(&mut jpeg, ImageOutputFormat::Jpeg(100))
+            .unwrap();
+        // neither an embedded nor a default profile: decoded as usual
+        assert!(decode_jpeg(jpeg.get_ref(), None).is_none());
+
+        let profile = IccProfile::new(ColorSpaceType::GRAY, linear_gray_profile()).unwrap();
+        let rgb = decode_jpeg(jpeg.get_ref(), Some(&profile)).unwrap();
+        assert_eq!(rgb.dimensions(), (2, 2));
+        assert!(rgb.get_pixel(0, 0)[0] > 160, "{:?}", rgb.get_pixel(0, 0));
+    }
+}
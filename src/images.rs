@@ -0,0 +1,369 @@
+//! Decoding of image resources to RGBA pixels.
+//!
+//! Bilevel images, JBIG2 and 1 bit ones, are decoded to stencils: opaque black where
+//! the image is painted, transparent elsewhere. Image objects paint stencils with their
+//! fill colour.
+
+use std::borrow::Cow;
+use std::io::Cursor;
+
+use image::{imageops, DynamicImage, GrayImage, Rgba, RgbaImage};
+use jbig2dec::Document;
+use tiff::tags::Tag;
+
+use crate::ccitt;
+use crate::icc::{self, IccProfile};
+
+const JBIG2_FILE_ID: [u8; 8] = [0x97, 0x4A, 0x42, 0x32, 0x0D, 0x0A, 0x1A, 0x0A];
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+/// The TIFF `Compression` of CCITT Group 4 fax data.
+const TIFF_CCITT_G4: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Bmp,
+    Tiff,
+    Gif,
+    Jbig2,
+}
+
+impl ImageFormat {
+    /// The format of an image file: by its magic bytes, else by the `Format` of its
+    /// resource, else by the extension of its name.
+    pub fn detect(data: &[u8], format: Option<&str>, file_name: &str) -> Option<ImageFormat> {
+        let sniffed = if data.starts_with(&PNG_SIGNATURE) {
+            Some(ImageFormat::Png)
+        } else if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(ImageFormat::Jpeg)
+        } else if data.starts_with(b"GIF8") {
+            Some(ImageFormat::Gif)
+        } else if data.starts_with(b"BM") {
+            Some(ImageFormat::Bmp)
+        } else if data.starts_with(b"II*\0") || data.starts_with(b"MM\0*") {
+            Some(ImageFormat::Tiff)
+        } else if data.starts_with(&JBIG2_FILE_ID) {
+            Some(ImageFormat::Jbig2)
+        } else {
+            None
+        };
+        sniffed
+            .or_else(|| format.and_then(ImageFormat::from_name))
+            .or_else(|| file_name.rsplit_once('.').and_then(|(_, ext)| ImageFormat::from_name(ext)))
+    }
+
+    /// The format of a `Format` attribute or a file extension, case insensitive.
+    fn from_name(name: &str) -> Option<ImageFormat> {
+        match name.trim().to_ascii_uppercase().as_str() {
+            "PNG" => Some(ImageFormat::Png),
+            "JPG" | "JPEG" => Some(ImageFormat::Jpeg),
+            "BMP" => Some(ImageFormat::Bmp),
+            "TIF" | "TIFF" => Some(ImageFormat::Tiff),
+            "GIF" => Some(ImageFormat::Gif),
+            // GBIG2 is written by some producers
+            "JB2" | "JBIG2" | "GBIG2" => Some(ImageFormat::Jbig2),
+            _ => None,
+        }
+    }
+}
+
+/// The pixels of an image resource.
+pub struct DecodedImage {
+    pub pixels: RgbaImage,
+    /// Whether the pixels are a stencil.
+    pub stencil: bool,
+}
+
+/// Decodes an image file, JPEGs without an embedded profile in `default_profile`. Images
+/// that fail are reported and `None`.
+pub fn decode(data: &[u8], format: ImageFormat, default_profile: Option<&IccProfile>) -> Option<DecodedImage> {
+    let image = |pixels| Some(DecodedImage { pixels, stencil: false });
+    let stencil = |pixels| Some(DecodedImage { pixels, stencil: true });
+    match format {
+        ImageFormat::Png => {
+            let decoded = decode_with(data, image::ImageFormat::Png)?;
+            if is_bilevel_png(data) { stencil(luma_stencil(&decoded)) } else { image(decoded.into_rgba8()) }
+        }
+        ImageFormat::Jpeg => match icc::decode_jpeg(data, default_profile) {
+            Some(rgb_image) => image(DynamicImage::ImageRgb8(rgb_image).into_rgba8()),
+            // CMYK without a profile is converted naively by the decoder
+            None => image(decode_with(data, image::ImageFormat::Jpeg)?.into_rgba8()),
+        },
+        ImageFormat::Bmp => {
+            let decoded = decode_with(data, image::ImageFormat::Bmp)?;
+            if is_bilevel_bmp(data) { stencil(luma_stencil(&decoded)) } else { image(decoded.into_rgba8()) }
+        }
+        ImageFormat::Gif => image(decode_with(data, image::ImageFormat::Gif)?.into_rgba8()),
+        ImageFormat::Tiff => decode_tiff(data),
+        ImageFormat::Jbig2 => stencil(decode_jbig2(data)?),
+    }
+}
+
+fn decode_with(data: &[u8], format: image::ImageFormat) -> Option<DynamicImage> {
+    image::load_from_memory_with_format(data, format)
+        .map_err(|e| log::warn!("{:?} decoding failed: {}", format, e))
+        .ok()
+}
+
+/// Decodes a TIFF, its CCITT Group 4 strips with the decoder of this crate.
+fn decode_tiff(data: &[u8]) -> Option<DecodedImage> {
+    let mut decoder = tiff::decoder::Decoder::new(Cursor::new(data))
+        .map_err(|e| log::warn!("TIFF decoding failed: {}", e))
+        .ok()?;
+    let mut tag = |tag: Tag| decoder.find_tag_unsigned::<u32>(tag).ok().flatten();
+    let compression = tag(Tag::Compression).unwrap_or(1);
+    let bilevel = tag(Tag::BitsPerSample) == Some(1);
+    // the photometric interpretation of fax data defaults to white is zero
+    let black_is_zero = tag(Tag::PhotometricInterpretation) == Some(1);
+    let lsb_first = tag(Tag::FillOrder) == Some(2);
+    let rows_per_strip = tag(Tag::RowsPerStrip);
+    if compression != TIFF_CCITT_G4 {
+        let decoded = decode_with(data, image::ImageFormat::Tiff)?;
+        return Some(if bilevel {
+            DecodedImage { pixels: luma_stencil(&decoded), stencil: true }
+        } else {
+            DecodedImage { pixels: decoded.into_rgba8(), stencil: false }
+        });
+    }
+
+    let (width, height) = decoder.dimensions().ok()?;
+    let rows_per_strip = rows_per_strip.unwrap_or(height).max(1);
+    let offsets = decoder.get_tag_u64_vec(Tag::StripOffsets).ok()?;
+    let counts = decoder.get_tag_u64_vec(Tag::StripByteCounts).ok()?;
+    let mut page = GrayImage::new(width, height);
+    for (strip, (&offset, &count)) in offsets.iter().zip(&counts).enumerate() {
+        let y = strip as u32 * rows_per_strip;
+        if y >= height {
+            break;
+        }
+        let rows = rows_per_strip.min(height - y);
+        let strip = match data.get(offset as usize..(offset + count) as usize) {
+            Some(strip) => strip,
+            None => {
+                log::warn!("TIFF strip {} out of the file", strip);
+                return None;
+            }
+        };
+        let rows = ccitt::decode_g4(strip, width, rows, lsb_first)
+            .map_err(|e| log::warn!("TIFF decoding failed: {}", e))
+            .ok()?;
+        imageops::replace(&mut page, &rows, 0, y as i64);
+    }
+    if black_is_zero {
+        imageops::invert(&mut page);
+    }
+    Some(DecodedImage { pixels: luma_stencil(&DynamicImage::ImageLuma8(page)), stencil: true })
+}
+
+/// Decodes a JBIG2 file or embedded stream to a stencil. Global segments, those of page
+/// 0, are shared by every page; the pages are painted over each other on the canvas of
+/// the first, scaled to it if their size differs.
+pub fn decode_jbig2(data: &[u8]) -> Option<RgbaImage> {
+    let mut reader = Cursor::new(jbig2_file(data));
+    let doc = match Document::from_reader(&mut reader) {
+        Ok(doc) => doc,
+        Err(e) => {
+            log::warn!("JBIG2 decoding failed: {:?}", e);
+            return None;
+        }
+    };
+    let mut pages = doc.images().iter()
+        .map(|page| bitmap_stencil(page.width(), page.height(), page.stride(), page.data()));
+    let mut stencil = pages.next()?;
+    for page in pages {
+        let page = if page.dimensions() == stencil.dimensions() {
+            page
+        } else {
+            imageops::resize(&page, stencil.width(), stencil.height(), imageops::FilterType::Nearest)
+        };
+        for (dst, src) in stencil.pixels_mut().zip(page.pixels()) {
+            dst[3] = dst[3].max(src[3]);
+        }
+    }
+    Some(stencil)
+}
+
+/// An embedded JBIG2 stream lacks the file header, it's given the header of a
+/// sequential file with an unknown number of pages.
+fn jbig2_file(data: &[u8]) -> Cow<'_, [u8]> {
+    if data.starts_with(&JBIG2_FILE_ID) {
+        return Cow::Borrowed(data);
+    }
+    let mut file = JBIG2_FILE_ID.to_vec();
+    // sequential organisation, number of pages unknown
+    file.push(0x03);
+    file.extend_from_slice(data);
+    Cow::Owned(file)
+}
+
+/// The stencil of a packed bitmap, most significant bit first and rows `stride` bytes
+/// apart, painted where the bits are set.
+fn bitmap_stencil(width: u32, height: u32, stride: u32, data: &[u8]) -> RgbaImage {
+    RgbaImage::from_fn(width, height, |x, y| {
+        let byte = data.get((y * stride + x / 8) as usize).copied().unwrap_or(0);
+        let alpha = if byte & (0x80 >> (x % 8)) != 0 { 255 } else { 0 };
+        Rgba([0, 0, 0, alpha])
+    })
+}
+
+/// Whether `data` is a PNG of 1 bit greyscale pixels.
+pub fn is_bilevel_png(data: &[u8]) -> bool {
+    // the IHDR chunk comes first, its bit depth and colour type follow the size
+    data.starts_with(&PNG_SIGNATURE) && data.get(12..16) == Some(b"IHDR")
+        && data.get(24) == Some(&1) && data.get(25) == Some(&0)
+}
+
+/// Whether `data` is a BMP of 1 bit pixels.
+fn is_bilevel_bmp(data: &[u8]) -> bool {
+    // the bit count follows the size in the old core header, the planes in the others
+    let bit_count_at = match data.get(14..18) {
+        Some(&[12, 0, 0, 0]) => 24,
+        Some(_) => 28,
+        None => return false,
+    };
+    data.get(bit_count_at..bit_count_at + 2) == Some(&[1, 0])
+}
+
+/// The stencil of a decoded bilevel image, painted where it's dark.
+pub fn luma_stencil(image: &DynamicImage) -> RgbaImage {
+    let luma = image.to_luma8();
+    RgbaImage::from_fn(luma.width(), luma.height(), |x, y| {
+        Rgba([0, 0, 0, 255 - luma.get_pixel(x, y)[0]])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bitmap_stencil, decode, decode_jbig2, is_bilevel_bmp, is_bilevel_png, jbig2_file, ImageFormat, JBIG2_FILE_ID};
+    use image::{GrayImage, ImageOutputFormat, Luma};
+    use std::io::Cursor;
+
+    /// A little endian TIFF of one strip, `entries` are tag, type and value.
+    fn tiff(entries: &[(u16, u16, u32)], strip: &[u8]) -> Vec<u8> {
+        let count = entries.len() + 2;
+        let strip_offset = (8 + 2 + count * 12 + 4) as u32;
+        let mut entries = entries.to_vec();
+        entries.extend([(273, 4, strip_offset), (279, 4, strip.len() as u32)]);
+        entries.sort();
+
+        let mut data = b"II*\0".to_vec();
+        data.extend(8u32.to_le_bytes());
+        data.extend((count as u16).to_le_bytes());
+        for (tag, type_, value) in entries {
+            data.extend(tag.to_le_bytes());
+            data.extend(type_.to_le_bytes());
+            data.extend(1u32.to_le_bytes());
+            // values of type SHORT are left aligned in the field
+            match type_ {
+                3 => data.extend([(value as u16).to_le_bytes(), [0, 0]].concat()),
+                _ => data.extend(value.to_le_bytes()),
+            }
+        }
+        data.extend(0u32.to_le_bytes());
+        data.extend(strip);
+        data
+    }
+
+    #[test]
+    fn test_bilevel_stencils() {
+        // 10 pixels wide, rows 2 bytes apart: set, clear, clear... then the last one set
+        let stencil = bitmap_stencil(10, 2, 2, &[0x80, 0x40, 0x00, 0x00]);
+        assert_eq!(stencil.get_pixel(0, 0)[3], 255);
+        assert_eq!(stencil.get_pixel(1, 0)[3], 0);
+        assert_eq!(stencil.get_pixel(9, 0)[3], 255);
+        assert!(stencil.rows().nth(1).unwrap().all(|p| p[3] == 0));
+
+        assert_eq!(jbig2_file(&[0, 0, 0, 1])[..9], [JBIG2_FILE_ID.as_slice(), &[0x03]].concat()[..]);
+        assert_eq!(jbig2_file(&JBIG2_FILE_ID).len(), 8);
+
+        let mut png = Cursor::new(Vec::new());
+        image::DynamicImage::ImageLuma8(GrayImage::from_pixel(2, 2, Luma([128])))
+            .write_to(&mut png, ImageOutputFormat::Png)
+            .unwrap();
+        assert!(!is_bilevel_png(png.get_ref()));
+        let mut bilevel = png.into_inner();
+        bilevel[24] = 1;
+        assert!(is_bilevel_png(&bilevel));
+    }
+
+    /// A JBIG2 segment header of short page association and no referred segments, then `data`.
+    fn jbig2_segment(number: u32, type_: u8, page: u8, data: &[u8]) -> Vec<u8> {
+        let mut segment = number.to_be_bytes().to_vec();
+        segment.extend([type_, 0, page]);
+        segment.extend((data.len() as u32).to_be_bytes());
+        segment.extend(data);
+        segment
+    }
+
+    /// The segments of a page `width` by `height` filled by one MMR coded generic region.
+    fn jbig2_page(first: u32, page: u8, width: u32, height: u32, mmr: &[u8]) -> Vec<u8> {
+        let mut info = [width.to_be_bytes(), height.to_be_bytes(), [0; 4], [0; 4]].concat();
+        info.extend([0, 0, 0]);
+        let mut region = [width.to_be_bytes(), height.to_be_bytes(), [0; 4], [0; 4]].concat();
+        // combination OR, then the MMR flag of the generic region
+        region.extend([0, 1]);
+        region.extend(mmr);
+        [
+            jbig2_segment(first, 48, page, &info),
+            jbig2_segment(first + 1, 38, page, &region),
+            jbig2_segment(first + 2, 49, page, &[]),
+        ].concat()
+    }
+
+    #[test]
+    fn test_jbig2_global_segments() {
+        // an embedded stream led by a global comment of page 0, as the global stream of a PDF
+        let mut comment = 0x2000_0000u32.to_be_bytes().to_vec();
+        comment.extend(b"Title\0Stencil\0\0");
+        let mut stream = jbig2_segment(0, 62, 0, &comment);
+        // page 1 is 8x1 with its first pixel set: horizontal mode, white 0 and black 1, then V0
+        stream.extend(jbig2_page(1, 1, 8, 1, &[0x26, 0xAA]));
+        // page 2 is 8x1 with its last pixel set: VL1, then V0
+        stream.extend(jbig2_page(4, 2, 8, 1, &[0x50]));
+
+        // both pages are painted
+        let stencil = decode_jbig2(&stream).unwrap();
+        assert_eq!(stencil.dimensions(), (8, 1));
+        assert_eq!(stencil.get_pixel(0, 0)[3], 255);
+        assert_eq!(stencil.get_pixel(7, 0)[3], 255);
+        assert!((1..7).all(|x| stencil.get_pixel(x, 0)[3] == 0));
+    }
+
+    #[test]
+    fn test_detect_and_decode() {
+        let mut png = Cursor::new(Vec::new());
+        image::DynamicImage::ImageLuma8(GrayImage::from_pixel(2, 2, Luma([0])))
+            .write_to(&mut png, ImageOutputFormat::Png)
+            .unwrap();
+        let png = png.into_inner();
+        // the content wins over the Format and the extension
+        assert_eq!(ImageFormat::detect(&png, Some("JPEG"), "Res/image.jpg"), Some(ImageFormat::Png));
+        assert_eq!(ImageFormat::detect(b"\0\0", Some("GBIG2"), "Res/image"), Some(ImageFormat::Jbig2));
+        assert_eq!(ImageFormat::detect(b"\0\0", None, "Res/IMAGE.TIF"), Some(ImageFormat::Tiff));
+        assert_eq!(ImageFormat::detect(b"\0\0", None, "Res/image"), None);
+        assert!(!decode(&png, ImageFormat::Png, None).unwrap().stencil);
+        // undecodable images are reported, not panics
+        assert!(decode(&png[..20], ImageFormat::Png, None).is_none());
+        assert!(decode(&png, ImageFormat::Gif, None).is_none());
+
+        let mut bmp = Cursor::new(Vec::new());
+        image::DynamicImage::ImageRgb8(image::RgbImage::new(2, 2))
+            .write_to(&mut bmp, ImageOutputFormat::Bmp)
+            .unwrap();
+        let mut bmp = bmp.into_inner();
+        assert_eq!(ImageFormat::detect(&bmp, None, ""), Some(ImageFormat::Bmp));
+        assert!(!is_bilevel_bmp(&bmp));
+        bmp[28] = 1;
+        assert!(is_bilevel_bmp(&bmp));
+
+        // 8 by 2 CCITT G4: 2 white, 3 black, 3 white, twice
+        let strip = [0b00101111, 0b01111000, 0b00000000, 0b00010000, 0b00000001];
+        let g4 = tiff(&[(256, 3, 8), (257, 3, 2), (258, 3, 1), (259, 3, 4), (262, 3, 0), (278, 3, 2)], &strip);
+        assert_eq!(ImageFormat::detect(&g4, None, ""), Some(ImageFormat::Tiff));
+        let decoded = decode(&g4, ImageFormat::Tiff, None).unwrap();
+        assert!(decoded.stencil);
+        let alpha: Vec<u8> = decoded.pixels.pixels().map(|p| p[3]).collect();
+        assert_eq!(alpha, [[0, 0, 255, 255, 255, 0, 0, 0]; 2].concat());
+    }
+}
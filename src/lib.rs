@@ -2,6 +2,11 @@ pub mod ofd;
 pub mod utils;
 pub mod backends;
 pub mod node_draw;
+pub mod icc;
+pub mod ccitt;
+pub mod images;
+pub mod style;
+pub mod shading;
 #[cfg(feature = "skia")]
 pub mod skia_draw;
 #[cfg(feature = "raqote")]
@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 
-use crate::ofd::{PhysicalBox, DrawParam};
+use crate::icc::{icc_color, MUTEX_ICC_PROFILES};
+use crate::ofd::{_Color, ColorSpace, ColorSpaceType, PhysicalBox, DrawParam, LineCap, LineJoin, PathObject, VectorG};
 use font_kit::family_name::FamilyName;
 use font_kit::font::Font;
 use font_kit::properties::{Properties, Weight};
@@ -8,42 +9,32 @@ use font_kit::source::SystemSource;
 use image::RgbaImage;
 use lazy_static::lazy_static;
 use send_wrapper::SendWrapper;
+use std::borrow::Borrow;
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
+use std::hash::Hash;
 use std::sync::Mutex;
+use std::thread::{self, ThreadId};
 
 const SONGTI_LIST: &[&str] = &["SimSun", "NSimSun", "Songti", "STSong", "SimSong", "FangSong", "Songti SC"];
 const KAITI_LIST: &[&str] = &["KaiTi", "Kai"];
 const HEITI_LIST: &[&str] = &["SimHei", "Microsoft YaHei", "Heiti SC", "STHeiti", "Heiti TC",];
 
 lazy_static! {
-    pub static ref MUTEX_RGB_IMAGE_RES: Mutex<HashMap<String, RgbaImage>> = {
-        let m = HashMap::new();
-        Mutex::new(m)
-    };
-    pub static ref MUTEX_IMAGE_RES: Mutex<HashMap<String, String>> = {
-        let m = HashMap::new();
-        Mutex::new(m)
-    };
-    pub static ref MUTEX_IMAGE_PNG_RES: Mutex<HashMap<String, Vec<u8>>> = {
-        let m = HashMap::new();
-        Mutex::new(m)
-    };
+    pub static ref MUTEX_RGB_IMAGE_RES: Mutex<ScopedMap<String, RgbaImage>> = Mutex::new(ScopedMap::new());
+    pub static ref MUTEX_IMAGE_RES: Mutex<ScopedMap<String, String>> = Mutex::new(ScopedMap::new());
+    /// Whether decoded images are stencils, painted with the fill colour where they're opaque.
+    pub static ref MUTEX_STENCIL_IMAGES: Mutex<ScopedMap<String, bool>> = Mutex::new(ScopedMap::new());
 
-    pub static ref MUTEX_RES_DRAW_PARAMS: Mutex<HashMap<String, DrawParam>> = {
-        let m = HashMap::new();
-        Mutex::new(m)
-    };
+    pub static ref MUTEX_RES_DRAW_PARAMS: Mutex<ScopedMap<String, DrawParam>> = Mutex::new(ScopedMap::new());
 
-    pub static ref RES_FONT_FAMILY_NAME_MAP: Mutex<HashMap<String, String>> = {
-        let m = HashMap::new();
-        Mutex::new(m)
-    };
+    pub static ref MUTEX_RES_COLOR_SPACES: Mutex<ScopedMap<String, ColorSpace>> = Mutex::new(ScopedMap::new());
 
-    pub static ref RES_FONT_ID_MAP: Mutex<HashMap<String, SendWrapper<Font>>> = {
-        let m = HashMap::new();
-        Mutex::new(m)
-    };
+    pub static ref MUTEX_RES_COMPOSITE_GRAPHIC_UNITS: Mutex<ScopedMap<String, VectorG>> = Mutex::new(ScopedMap::new());
+
+    pub static ref RES_FONT_FAMILY_NAME_MAP: Mutex<ScopedMap<String, String>> = Mutex::new(ScopedMap::new());
+
+    pub static ref RES_FONT_ID_MAP: Mutex<ScopedMap<String, SendWrapper<Font>>> = Mutex::new(ScopedMap::new());
 
     pub static ref FONT_NAME_2_FONT_MAP: Mutex<HashMap<String, SendWrapper<Font>>> = {
         let m = HashMap::new();
@@ -96,9 +87,9 @@ pub fn get_font_from_family_name(family_name: &str) -> Font {
         .take();
 }
 
-const PATH_OBJECT: &'static str = "PathObject";
-const TEXT_OBJECT: &'static str = "TextObject";
-const IMAGE_OBJECT: &'static str = "ImageObject";
+pub const PATH_OBJECT: &'static str = "PathObject";
+pub const TEXT_OBJECT: &'static str = "TextObject";
+pub const IMAGE_OBJECT: &'static str = "ImageObject";
 pub const PPMM: f32 = 7.559; // pixel per mm, ppi = 192; 25.4mm = 1inch
 
 // const DRAW_OBJECT: Vec<&str> = vec![PATH_OBJECT, TEXT_OBJECT,  IMAGE_OBJECT];
@@ -113,7 +104,119 @@ pub fn boundary_to_rect(boundary: &String) -> PhysicalBox {
     }
 }
 
-#[derive(Debug, Clone)]
+/// Resources by ID: those registered for the document, shadowed by those registered in the
+/// scopes a thread opened, see `ResourceScope`, for that thread only.
+pub struct ScopedMap<K, V> {
+    base: HashMap<K, V>,
+    scopes: HashMap<ThreadId, Vec<HashMap<K, V>>>,
+}
+
+impl<K, V> Default for ScopedMap<K, V> {
+    fn default() -> Self {
+        ScopedMap {
+            base: HashMap::new(),
+            scopes: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash, V> ScopedMap<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The maps visible to the current thread, innermost scope first.
+    fn visible_maps(&self) -> impl Iterator<Item = &HashMap<K, V>> {
+        self.scopes.get(&thread::current().id())
+            .into_iter()
+            .flat_map(|scopes| scopes.iter().rev())
+            .chain(std::iter::once(&self.base))
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: Eq + Hash + ?Sized
+    {
+        self.visible_maps().find_map(|map| map.get(key))
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+        where K: Borrow<Q>, Q: Eq + Hash + ?Sized
+    {
+        self.get(key).is_some()
+    }
+
+    /// Inserts into the innermost scope of the current thread, the document's if it has none.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        match self.scopes.get_mut(&thread::current().id()).and_then(|scopes| scopes.last_mut()) {
+            Some(scope) => scope.insert(key, value),
+            None => self.base.insert(key, value),
+        }
+    }
+
+    /// The entries visible to the current thread, to resolve references between them.
+    pub fn visible(&self) -> HashMap<K, V>
+        where K: Clone, V: Clone
+    {
+        let mut visible = HashMap::new();
+        for map in self.visible_maps() {
+            for (key, value) in map {
+                visible.entry(key.clone()).or_insert_with(|| value.clone());
+            }
+        }
+        visible
+    }
+
+    pub(crate) fn open_scope(&mut self) {
+        self.scopes.entry(thread::current().id()).or_default().push(HashMap::new());
+    }
+
+    pub(crate) fn close_scope(&mut self) {
+        let id = thread::current().id();
+        if let Some(scopes) = self.scopes.get_mut(&id) {
+            scopes.pop();
+            if scopes.is_empty() {
+                self.scopes.remove(&id);
+            }
+        }
+    }
+}
+
+/// A scope of resources, those of a page, registered from its opening and visible to the
+/// thread opening it until it's dropped.
+pub struct ResourceScope {
+    _private: (),
+}
+
+impl ResourceScope {
+    pub fn open() -> Self {
+        MUTEX_RGB_IMAGE_RES.lock().unwrap().open_scope();
+        MUTEX_IMAGE_RES.lock().unwrap().open_scope();
+        MUTEX_STENCIL_IMAGES.lock().unwrap().open_scope();
+        MUTEX_RES_DRAW_PARAMS.lock().unwrap().open_scope();
+        MUTEX_RES_COLOR_SPACES.lock().unwrap().open_scope();
+        MUTEX_RES_COMPOSITE_GRAPHIC_UNITS.lock().unwrap().open_scope();
+        RES_FONT_FAMILY_NAME_MAP.lock().unwrap().open_scope();
+        RES_FONT_ID_MAP.lock().unwrap().open_scope();
+        MUTEX_ICC_PROFILES.lock().unwrap().open_scope();
+        ResourceScope { _private: () }
+    }
+}
+
+impl Drop for ResourceScope {
+    fn drop(&mut self) {
+        MUTEX_RGB_IMAGE_RES.lock().unwrap().close_scope();
+        MUTEX_IMAGE_RES.lock().unwrap().close_scope();
+        MUTEX_STENCIL_IMAGES.lock().unwrap().close_scope();
+        MUTEX_RES_DRAW_PARAMS.lock().unwrap().close_scope();
+        MUTEX_RES_COLOR_SPACES.lock().unwrap().close_scope();
+        MUTEX_RES_COMPOSITE_GRAPHIC_UNITS.lock().unwrap().close_scope();
+        RES_FONT_FAMILY_NAME_MAP.lock().unwrap().close_scope();
+        RES_FONT_ID_MAP.lock().unwrap().close_scope();
+        MUTEX_ICC_PROFILES.lock().unwrap().close_scope();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct OfdColor {
     pub(crate) r: u8,
     pub(crate) g: u8,
@@ -133,14 +236,164 @@ impl Default for OfdColor {
 }
 
 
+/// Effective stroke attributes of a path, after falling back to its draw param.
+#[derive(Debug, Clone)]
+pub struct LineStyle {
+    pub width: f32,
+    pub join: LineJoin,
+    pub cap: LineCap,
+    pub miter_limit: f32,
+    pub dash_offset: f32,
+    pub dash_pattern: Vec<f32>,
+}
+
+impl Default for LineStyle {
+    fn default() -> Self {
+        LineStyle {
+            width: 0.5,
+            join: LineJoin::Miter,
+            cap: LineCap::Butt,
+            miter_limit: 3.528,
+            dash_offset: 0.,
+            dash_pattern: vec![],
+        }
+    }
+}
+
+impl LineStyle {
+    pub fn from_path_object(path_object: &PathObject, draw_param: Option<&DrawParam>) -> Self {
+        let default = LineStyle::default();
+        LineStyle {
+            width: path_object.line_width
+                .or(draw_param.and_then(|dp| dp.line_width))
+                .unwrap_or(default.width),
+            join: path_object.join
+                .or(draw_param.and_then(|dp| dp.join))
+                .unwrap_or(default.join),
+            cap: path_object.cap
+                .or(draw_param.and_then(|dp| dp.cap))
+                .unwrap_or(default.cap),
+            miter_limit: path_object.miter_limit
+                .or(draw_param.and_then(|dp| dp.miter_limit))
+                .unwrap_or(default.miter_limit),
+            dash_offset: path_object.dash_offset
+                .or(draw_param.and_then(|dp| dp.dash_offset))
+                .unwrap_or(default.dash_offset),
+            dash_pattern: path_object.dash_pattern.clone()
+                .or(draw_param.and_then(|dp| dp.dash_pattern.clone()))
+                .unwrap_or(default.dash_pattern),
+        }
+    }
+
+    /// Dash intervals as on/off pairs; an odd pattern is repeated once to make it even.
+    pub fn dash_intervals(&self) -> Vec<f32> {
+        if self.dash_pattern.len() % 2 == 1 {
+            self.dash_pattern.repeat(2)
+        } else {
+            self.dash_pattern.clone()
+        }
+    }
+}
+
+
+/// Parses an RGB colour value with an optional alpha, a malformed one is reported and black.
 pub fn ofd_color_from_v(s: &String) -> OfdColor {
-    let mut iter = s.split_whitespace().into_iter();
-    OfdColor {
-        r: iter.next().unwrap().parse().unwrap(),
-        g: iter.next().unwrap().parse().unwrap(),
-        b: iter.next().unwrap().parse().unwrap(),
-        a: iter.next().unwrap_or("255").parse().unwrap(),
+    color_components(s)
+        .and_then(|components| color_space_to_rgb(None, &components))
+        .unwrap_or_else(|| {
+            log::warn!("invalid colour value \"{}\"", s);
+            OfdColor::default()
+        })
+}
+
+/// Scales `alpha` by another alpha, both 0 - 255.
+pub fn mul_alpha(alpha: u8, by: u8) -> u8 {
+    ((alpha as u32 * by as u32 + 127) / 255) as u8
+}
+
+/// Parses the channels of a colour value, each either decimal or `#` prefixed hexadecimal.
+fn color_components(s: &str) -> Option<Vec<f32>> {
+    s.split_whitespace()
+        .map(|v| match v.strip_prefix('#') {
+            Some(hex) => u32::from_str_radix(hex, 16).ok().map(|v| v as f32),
+            None => v.parse::<f32>().ok(),
+        })
+        .collect()
+}
+
+fn cmyk_to_rgb(c: f32, m: f32, y: f32, k: f32) -> (f32, f32, f32) {
+    (
+        (1. - c) * (1. - k),
+        (1. - m) * (1. - k),
+        (1. - y) * (1. - k),
+    )
+}
+
+/// Converts the channels of a colour to device RGB according to its colour space, through
+/// its ICC profile when one was loaded.
+///
+/// Without a colour space the value is taken as 8 bit RGB, with an optional fourth alpha channel.
+pub fn color_space_to_rgb(color_space: Option<&ColorSpace>, components: &[f32]) -> Option<OfdColor> {
+    let to_u8 = |v: f32| (v.clamp(0., 1.) * 255.).round() as u8;
+    let color_space = match color_space {
+        Some(color_space) => color_space,
+        None => {
+            if components.len() < 3 {
+                return None;
+            }
+            return Some(OfdColor {
+                r: to_u8(components[0] / 255.),
+                g: to_u8(components[1] / 255.),
+                b: to_u8(components[2] / 255.),
+                a: components.get(3).map_or(0xff, |a| to_u8(a / 255.)),
+            });
+        }
+    };
+    let max = ((1u32 << color_space.bits_per_component.clamp(1, 16)) - 1) as f32;
+    let v: Vec<f32> = components.iter().map(|c| c / max).collect();
+    if let Some((r, g, b)) = icc_color(&color_space.id, &v) {
+        return Some(OfdColor { r, g, b, a: 0xff });
     }
+    let (r, g, b) = match color_space.type_ {
+        ColorSpaceType::GRAY if !v.is_empty() => (v[0], v[0], v[0]),
+        ColorSpaceType::RGB if v.len() >= 3 => (v[0], v[1], v[2]),
+        ColorSpaceType::CMYK if v.len() >= 4 => cmyk_to_rgb(v[0], v[1], v[2], v[3]),
+        _ => return None,
+    };
+    Some(OfdColor {
+        r: to_u8(r),
+        g: to_u8(g),
+        b: to_u8(b),
+        a: 0xff,
+    })
+}
+
+/// Converts an OFD colour to device RGB through its `ColorSpace` resource, looking the
+/// value up in the palette for indexed colours. `None` if the colour carries no usable value.
+pub fn ofd_color(color: &_Color) -> Option<OfdColor> {
+    let color_space = color.color_space.as_ref().and_then(|id| {
+        let color_space = MUTEX_RES_COLOR_SPACES.lock().unwrap().get(id).cloned();
+        if color_space.is_none() {
+            log::warn!("ColorSpace {} not found", id);
+        }
+        color_space
+    });
+    let value = match (color.index, &color_space) {
+        (Some(index), Some(cs)) if !cs.palette.is_empty() => {
+            let value = cs.palette.get(index);
+            if value.is_none() {
+                log::warn!("Index {} out of the palette of ColorSpace {}", index, cs.id);
+            }
+            value
+        }
+        _ => color.value.as_ref(),
+    }?;
+    let rgb = color_components(value)
+        .and_then(|components| color_space_to_rgb(color_space.as_ref(), &components));
+    if rgb.is_none() {
+        log::warn!("invalid colour value \"{}\" in {:?}", value, color_space);
+    }
+    rgb.map(|rgb| OfdColor { a: mul_alpha(rgb.a, color.alpha), ..rgb })
 }
 
 
@@ -176,23 +429,117 @@ impl Display for PathToken {
     }
 }
 
+/// Splits AbbreviatedData into operators and operands. A command with an operand that
+/// isn't a number is reported and skipped whole, operator included.
 pub fn abbreviate_data(data: &String) -> Vec<PathToken> {
-    data.split_whitespace()
-        .map(|s| match s {
-            "M" | "L" | "B" | "A" | "Q" | "C" => PathToken {
-                tag: Tag::C,
-                token: _PathToken {
-                    op: s.chars().next().unwrap(),
-                },
+    let mut tokens = Vec::new();
+    let mut command_start = 0;
+    let mut malformed = false;
+    let mut reported = false;
+    for s in data.split_whitespace() {
+        match s {
+            "S" | "M" | "L" | "B" | "A" | "Q" | "C" => {
+                command_start = tokens.len();
+                malformed = false;
+                tokens.push(PathToken {
+                    tag: Tag::C,
+                    token: _PathToken {
+                        op: s.chars().next().unwrap(),
+                    },
+                });
+            }
+            _ if malformed => {}
+            v => match v.parse() {
+                Ok(v) => tokens.push(PathToken {
+                    tag: Tag::F,
+                    token: _PathToken { v },
+                }),
+                Err(_) => {
+                    if !reported {
+                        log::warn!("OFD path token [{}] invalid, its command is skipped in: {}", v, data);
+                        reported = true;
+                    }
+                    tokens.truncate(command_start);
+                    malformed = true;
+                }
             },
-            v => PathToken {
-                tag: Tag::F,
-                token: _PathToken {
-                    v: v.parse().unwrap(),
-                },
+        }
+    }
+    tokens
+}
+
+/// A single AbbreviatedData operator with its operands, see GB/T 33190 9.3.4.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PathCommand {
+    /// `S` and `M`, start a new subpath at the point.
+    MoveTo(f32, f32),
+    LineTo(f32, f32),
+    QuadTo(f32, f32, f32, f32),
+    CubicTo(f32, f32, f32, f32, f32, f32),
+    ArcTo {
+        rx: f32,
+        ry: f32,
+        x_axis_rotation: f32,
+        large_arc: bool,
+        sweep: bool,
+        x: f32,
+        y: f32,
+    },
+    Close,
+}
+
+pub fn path_commands(data: &String) -> Vec<PathCommand> {
+    let tokens = abbreviate_data(data);
+    let mut commands = Vec::new();
+    let mut idx = 0;
+    while idx < tokens.len() {
+        let token = &tokens[idx];
+        idx += 1;
+        let op = match token.tag {
+            Tag::C => unsafe { token.token.op },
+            Tag::F => continue,
+        };
+        let argc = match op {
+            'S' | 'M' | 'L' => 2,
+            'Q' => 4,
+            'B' => 6,
+            'A' => 7,
+            _ => 0,
+        };
+        let args: Option<Vec<f32>> = tokens.get(idx..idx + argc).and_then(|operands| {
+            operands.iter()
+                .map(|t| match t.tag {
+                    Tag::F => Some(unsafe { t.token.v }),
+                    Tag::C => None,
+                })
+                .collect()
+        });
+        let v = match args {
+            Some(v) => v,
+            None => {
+                log::warn!("OFD path operator [{}] lacks operands in: {}", op, data);
+                break;
+            }
+        };
+        idx += argc;
+        commands.push(match op {
+            'S' | 'M' => PathCommand::MoveTo(v[0], v[1]),
+            'L' => PathCommand::LineTo(v[0], v[1]),
+            'Q' => PathCommand::QuadTo(v[0], v[1], v[2], v[3]),
+            'B' => PathCommand::CubicTo(v[0], v[1], v[2], v[3], v[4], v[5]),
+            'A' => PathCommand::ArcTo {
+                rx: v[0],
+                ry: v[1],
+                x_axis_rotation: v[2],
+                large_arc: v[3] as u32 > 0,
+                sweep: v[4] as i32 > 0,
+                x: v[5],
+                y: v[6],
             },
-        })
-        .collect()
+            _ => PathCommand::Close,
+        });
+    }
+    commands
 }
 
 
@@ -212,26 +559,282 @@ pub fn delta_to_vec(data: &String) -> Vec<f32> {
     vec
 }
 
-pub fn get_color_from_draw_param(draw_param_id: Option<&String>) -> (Option<OfdColor>, Option<OfdColor>) {
-    let draw_param = draw_param_id.map_or(
-        None,
-        |it| {
-            Some(MUTEX_RES_DRAW_PARAMS.lock().unwrap().get(it).unwrap().clone())
+/// Looks up the effective draw param of a graphic unit.
+///
+/// The object's own `DrawParam` inherits from the enclosing layer's one, both already have
+/// their `Relative` chains resolved; inline attributes of the object override either of them.
+/// Unknown ids are reported and ignored.
+pub fn get_draw_param(layer_draw_param_id: Option<&String>, object_draw_param_id: Option<&String>)
+    -> Option<DrawParam>
+{
+    let draw_params = MUTEX_RES_DRAW_PARAMS.lock().unwrap();
+    let lookup = |id: &String| {
+        let draw_param = draw_params.get(id).cloned();
+        if draw_param.is_none() {
+            log::warn!("DrawParam {} not found", id);
+        }
+        draw_param
+    };
+    let layer_draw_param = layer_draw_param_id.and_then(lookup);
+    match object_draw_param_id.and_then(lookup) {
+        Some(mut draw_param) => {
+            if let Some(layer_draw_param) = &layer_draw_param {
+                draw_param.inherit(layer_draw_param);
+            }
+            Some(draw_param)
         }
+        None => layer_draw_param,
+    }
+}
+
+/// Effective colour of a graphic unit: the inline colour wins over the draw param's one,
+/// `None` if neither of them carries a value.
+pub fn resolve_color(color: Option<&_Color>, draw_param_color: Option<&_Color>) -> Option<OfdColor> {
+    color.and_then(ofd_color)
+        .or_else(|| draw_param_color.and_then(ofd_color))
+}
+
+
+/// Converts an OFD/SVG style elliptical arc (endpoint parameterization) into cubic bezier
+/// segments `[c1x, c1y, c2x, c2y, x, y]`, for backends without a rotated elliptical arc.
+///
+/// `x_axis_rotation` is in degrees, `sweep` true means the arc goes in positive-angle
+/// (clockwise on a y-down page) direction. See SVG 1.1 implementation notes F.6.5.
+pub fn arc_to_cubics(
+    from: (f32, f32),
+    radius: (f32, f32),
+    x_axis_rotation: f32,
+    large_arc: bool,
+    sweep: bool,
+    to: (f32, f32),
+) -> Vec<[f32; 6]> {
+    let (x1, y1) = (from.0 as f64, from.1 as f64);
+    let (x2, y2) = (to.0 as f64, to.1 as f64);
+    let (mut rx, mut ry) = ((radius.0 as f64).abs(), (radius.1 as f64).abs());
+    if x1 == x2 && y1 == y2 {
+        return vec![];
+    }
+    if rx == 0. || ry == 0. {
+        return vec![[from.0, from.1, to.0, to.1, to.0, to.1]];
+    }
+
+    let phi = (x_axis_rotation as f64).to_radians();
+    let (sin_phi, cos_phi) = phi.sin_cos();
+
+    // Step 1: compute (x1', y1')
+    let dx = (x1 - x2) / 2.;
+    let dy = (y1 - y2) / 2.;
+    let x1p = cos_phi * dx + sin_phi * dy;
+    let y1p = -sin_phi * dx + cos_phi * dy;
+
+    // Correct out-of-range radii
+    let lambda = (x1p * x1p) / (rx * rx) + (y1p * y1p) / (ry * ry);
+    if lambda > 1. {
+        rx *= lambda.sqrt();
+        ry *= lambda.sqrt();
+    }
+
+    // Step 2: compute (cx', cy')
+    let num = rx * rx * ry * ry - rx * rx * y1p * y1p - ry * ry * x1p * x1p;
+    let den = rx * rx * y1p * y1p + ry * ry * x1p * x1p;
+    let mut coef = (num / den).max(0.).sqrt();
+    if large_arc == sweep {
+        coef = -coef;
+    }
+    let cxp = coef * rx * y1p / ry;
+    let cyp = -coef * ry * x1p / rx;
+
+    // Step 3: compute (cx, cy) from (cx', cy')
+    let cx = cos_phi * cxp - sin_phi * cyp + (x1 + x2) / 2.;
+    let cy = sin_phi * cxp + cos_phi * cyp + (y1 + y2) / 2.;
+
+    // Step 4: compute start angle and sweep angle
+    let angle = |ux: f64, uy: f64, vx: f64, vy: f64| (ux * vy - uy * vx).atan2(ux * vx + uy * vy);
+    let theta1 = angle(1., 0., (x1p - cxp) / rx, (y1p - cyp) / ry);
+    let mut delta = angle(
+        (x1p - cxp) / rx, (y1p - cyp) / ry,
+        (-x1p - cxp) / rx, (-y1p - cyp) / ry,
     );
-    let dp_fill_color = draw_param.as_ref().map_or(
-        None,
-        |dp| dp.fill_color.clone().map_or(
-            None,
-            |c| Some(ofd_color_from_v(&c.value.unwrap_or("0 0 0".to_string())))
+    if !sweep && delta > 0. {
+        delta -= 2. * std::f64::consts::PI;
+    } else if sweep && delta < 0. {
+        delta += 2. * std::f64::consts::PI;
+    }
+
+    // split into segments of at most 90 degrees
+    let segments = (delta.abs() / std::f64::consts::FRAC_PI_2).ceil().max(1.) as usize;
+    let step = delta / segments as f64;
+    let k = 4. / 3. * (step / 4.).tan();
+
+    let point = |t: f64| {
+        let (sin_t, cos_t) = t.sin_cos();
+        (
+            cx + rx * cos_t * cos_phi - ry * sin_t * sin_phi,
+            cy + rx * cos_t * sin_phi + ry * sin_t * cos_phi,
         )
-    );
-    let dp_stroke_color = draw_param.as_ref().map_or(
-        None,
-        |dp| dp.stroke_color.clone().map_or(
-            None,
-            |c| Some(ofd_color_from_v(&c.value.unwrap_or("0 0 0".to_string())))
+    };
+    let derivative = |t: f64| {
+        let (sin_t, cos_t) = t.sin_cos();
+        (
+            -rx * sin_t * cos_phi - ry * cos_t * sin_phi,
+            -rx * sin_t * sin_phi + ry * cos_t * cos_phi,
         )
-    );
-    (dp_fill_color, dp_stroke_color)
-}
\ No newline at end of file
+    };
+
+    let mut cubics = Vec::with_capacity(segments);
+    let mut t = theta1;
+    for i in 0..segments {
+        let t_next = t + step;
+        let (p0x, p0y) = point(t);
+        let (d0x, d0y) = derivative(t);
+        let (p3x, p3y) = if i + 1 == segments { (x2, y2) } else { point(t_next) };
+        let (d3x, d3y) = derivative(t_next);
+        cubics.push([
+            (p0x + k * d0x) as f32,
+            (p0y + k * d0y) as f32,
+            (p3x - k * d3x) as f32,
+            (p3y - k * d3y) as f32,
+            p3x as f32,
+            p3y as f32,
+        ]);
+        t = t_next;
+    }
+    cubics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{abbreviate_data, arc_to_cubics, get_draw_param, ofd_color, ofd_color_from_v, path_commands, resolve_color, OfdColor, PathCommand, ResourceScope, Tag, MUTEX_RES_COLOR_SPACES, MUTEX_RES_DRAW_PARAMS};
+    use crate::ofd::{_Color, ColorSpace, DrawParam, PathObject};
+
+    #[test]
+    fn test_draw_param_precedence() {
+        // the resources registered here vanish with the scope
+        let _scope = ResourceScope::open();
+        for (id, xml) in [
+            ("test_layer", r#"<DrawParam ID="test_layer" LineWidth="1"><FillColor Value="1 1 1"/><StrokeColor Value="1 1 1"/></DrawParam>"#),
+            ("test_object", r#"<DrawParam ID="test_object" LineWidth="2"><StrokeColor Value="2 2 2"/></DrawParam>"#),
+        ] {
+            let draw_param: DrawParam = quick_xml::de::from_str(xml).unwrap();
+            MUTEX_RES_DRAW_PARAMS.lock().unwrap().insert(id.to_string(), draw_param);
+        }
+        let path_object: PathObject = quick_xml::de::from_str(
+            r#"<PathObject ID="1" Boundary="0 0 1 1" DrawParam="test_object">
+                <FillColor Value="3 3 3"/><AbbreviatedData>M 0 0</AbbreviatedData>
+            </PathObject>"#
+        ).unwrap();
+
+        let draw_param = get_draw_param(
+            Some(&String::from("test_layer")),
+            path_object.draw_param.as_ref(),
+        ).unwrap();
+        assert_eq!(draw_param.line_width, Some(2.));
+        let fill = resolve_color(path_object.fill_color.as_ref(), draw_param.fill_color.as_ref()).unwrap();
+        let stroke = resolve_color(path_object.stroke_color.as_ref(), draw_param.stroke_color.as_ref()).unwrap();
+        assert_eq!((fill.r, stroke.r), (3, 2));
+
+        let layer_only = get_draw_param(Some(&String::from("test_layer")), None).unwrap();
+        assert_eq!(layer_only.line_width, Some(1.));
+        assert!(get_draw_param(Some(&String::from("test_missing")), None).is_none());
+    }
+
+    #[test]
+    fn test_abbreviate_data_start_and_close() {
+        let tokens = abbreviate_data(&String::from("S 0 0 L 1 0 L 1 1 C M 2 2 L 3 3"));
+        let ops: String = tokens.iter()
+            .filter(|t| matches!(t.tag, Tag::C))
+            .map(|t| unsafe { t.token.op })
+            .collect();
+        assert_eq!(ops, "SLLCML");
+    }
+
+    #[test]
+    fn test_malformed_path_data() {
+        let commands = path_commands(&String::from("M 0 0 L 1 x 1 L 2 2 C B 1 1"));
+        assert_eq!(commands, vec![
+            PathCommand::MoveTo(0., 0.),
+            PathCommand::LineTo(2., 2.),
+            PathCommand::Close,
+        ]);
+        assert_eq!(ofd_color_from_v(&String::from("255 x")), OfdColor::default());
+        assert_eq!(ofd_color_from_v(&String::from("1 2 3")).b, 3);
+    }
+
+    #[test]
+    fn test_color_spaces() {
+        let _scope = ResourceScope::open();
+        for xml in [
+            r#"<ColorSpace ID="test_gray" Type="GRAY"/>"#,
+            r#"<ColorSpace ID="test_cmyk" Type="CMYK"/>"#,
+            r#"<ColorSpace ID="test_rgb16" Type="RGB" BitsPerComponent="16"/>"#,
+            r#"<ColorSpace ID="test_indexed" Type="RGB"><Palette><CV>255 0 0</CV><CV>#00 #FF #00</CV></Palette></ColorSpace>"#,
+        ] {
+            let color_space: ColorSpace = quick_xml::de::from_str(xml).unwrap();
+            MUTEX_RES_COLOR_SPACES.lock().unwrap().insert(color_space.id.clone(), color_space);
+        }
+        let rgb = |xml: &str| {
+            let color: _Color = quick_xml::de::from_str(xml).unwrap();
+            ofd_color(&color).map(|c| (c.r, c.g, c.b, c.a))
+        };
+
+        assert_eq!(rgb(r#"<FillColor Value="128 0 0"/>"#), Some((128, 0, 0, 255)));
+        assert_eq!(rgb(r#"<FillColor Value="128 0 0 64"/>"#), Some((128, 0, 0, 64)));
+        assert_eq!(rgb(r#"<FillColor Value="51" ColorSpace="test_gray"/>"#), Some((51, 51, 51, 255)));
+        assert_eq!(rgb(r#"<FillColor Value="0 255 255 0" ColorSpace="test_cmyk"/>"#), Some((255, 0, 0, 255)));
+        assert_eq!(rgb(r#"<FillColor Value="0 0 0 255" ColorSpace="test_cmyk"/>"#), Some((0, 0, 0, 255)));
+        assert_eq!(rgb(r#"<FillColor Value="65535 0 32896" ColorSpace="test_rgb16"/>"#), Some((255, 0, 128, 255)));
+        assert_eq!(rgb(r#"<FillColor Index="1" ColorSpace="test_indexed"/>"#), Some((0, 255, 0, 255)));
+        assert_eq!(rgb(r#"<FillColor Index="5" ColorSpace="test_indexed"/>"#), None);
+        assert_eq!(rgb(r#"<FillColor Value="1 2" ColorSpace="test_cmyk"/>"#), None);
+        assert_eq!(rgb(r#"<FillColor Value="red"/>"#), None);
+        assert_eq!(rgb(r#"<FillColor/>"#), None);
+
+        assert_eq!(rgb(r#"<FillColor Value="128 0 0" Alpha="128"/>"#), Some((128, 0, 0, 128)));
+        assert_eq!(rgb(r#"<FillColor Value="128 0 0 128" Alpha="128"/>"#), Some((128, 0, 0, 64)));
+    }
+
+    #[test]
+    fn test_path_commands() {
+        let commands = path_commands(&String::from(
+            "S 0 0 L 1 0 Q 1 1 2 2 B 0 1 2 3 4 5 A 5 5 30 1 0 10 0 C M 7 7 L"
+        ));
+        assert_eq!(commands, vec![
+            PathCommand::MoveTo(0., 0.),
+            PathCommand::LineTo(1., 0.),
+            PathCommand::QuadTo(1., 1., 2., 2.),
+            PathCommand::CubicTo(0., 1., 2., 3., 4., 5.),
+            PathCommand::ArcTo {
+                rx: 5.,
+                ry: 5.,
+                x_axis_rotation: 30.,
+                large_arc: true,
+                sweep: false,
+                x: 10.,
+                y: 0.,
+            },
+            PathCommand::Close,
+            PathCommand::MoveTo(7., 7.),
+        ]);
+    }
+
+    #[test]
+    fn test_arc_to_cubics_half_circle() {
+        // half circle from (0, 0) to (10, 0) with radius 5, clockwise on a y-down page
+        let cubics = arc_to_cubics((0., 0.), (5., 5.), 0., false, true, (10., 0.));
+        assert_eq!(cubics.len(), 2);
+        let last = cubics.last().unwrap();
+        assert_eq!((last[4], last[5]), (10., 0.));
+        // the midpoint of the arc is the top of the circle, (5, -5)
+        let mid = cubics[0];
+        assert!((mid[4] - 5.).abs() < 1e-4 && (mid[5] + 5.).abs() < 1e-4, "{:?}", mid);
+    }
+
+    #[test]
+    fn test_arc_to_cubics_large_arc() {
+        let cubics = arc_to_cubics((0., 0.), (5., 5.), 0., true, false, (5., 5.));
+        // three quarters of a circle
+        assert_eq!(cubics.len(), 3);
+        let last = cubics.last().unwrap();
+        assert_eq!((last[4], last[5]), (5., 5.));
+    }
+}
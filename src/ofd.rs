@@ -1,13 +1,15 @@
 #![allow(dead_code)]
 
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
 
-use crate::node_draw::{boundary_to_rect, get_font_from_family_name, MUTEX_IMAGE_PNG_RES, MUTEX_IMAGE_RES, MUTEX_RES_DRAW_PARAMS, MUTEX_RGB_IMAGE_RES, PPMM, RES_FONT_FAMILY_NAME_MAP, RES_FONT_ID_MAP};
+use crate::node_draw::{get_font_from_family_name, ResourceScope, MUTEX_IMAGE_RES, MUTEX_RES_COLOR_SPACES, MUTEX_RES_COMPOSITE_GRAPHIC_UNITS, MUTEX_RES_DRAW_PARAMS, MUTEX_RGB_IMAGE_RES, MUTEX_STENCIL_IMAGES, PPMM, RES_FONT_FAMILY_NAME_MAP, RES_FONT_ID_MAP};
 use font_kit::family_name::FamilyName;
 use font_kit::font::Font;
 use font_kit::properties::{Properties, Weight};
 use font_kit::source::SystemSource;
-use jbig2dec::Document;
 use send_wrapper::SendWrapper;
 use serde::{Deserialize, Deserializer, Serialize};
 use std::fs::File;
@@ -18,7 +20,11 @@ use zip::read::ZipFile;
 use zip::result::ZipError;
 use zip::{ZipArchive};
 use crate::backends;
-use crate::backends::{DrawBackend};
+use crate::backends::{DrawBackend, RenderOptions};
+use crate::icc;
+use crate::images::{self, ImageFormat};
+use crate::style::{image_transform, object_transform, ResolvedClip};
+use crate::utils::{loc_dir, resolve_loc};
 
 pub const OFD_XML: &'static str = "OFD.xml";
 pub const OFD_NAMESPACE_URL: &'static str = "http://www.ofdspec.org/2016";
@@ -39,6 +45,7 @@ pub struct OFDFile {
     pub doc_body: Option<Element>,
     pub doc_root: String,
     pub ofd_doc: Option<OFDDoc>,
+    render_options: RenderOptions,
 }
 
 impl OFDFile {
@@ -55,6 +62,7 @@ impl OFDFile {
             doc_body: None,
             doc_root: String::new(),
             ofd_doc: None,
+            render_options: RenderOptions::default(),
         };
         ofd_file.inflate();
         ofd_file.parse_resources();
@@ -72,16 +80,17 @@ impl OFDFile {
         let _info = doc_body_v.get_child("DocInfo").expect("DocInfo not found");
         // println!("DocInfo: {:?}", _info);
 
-        let doc_root = String::from(
-            doc_body_v
-                .get_child("DocRoot")
-                .expect("DocRoot not found")
-                .get_text()
-                .unwrap(),
-        );
-        self.doc_root = Path::new(&doc_root).parent().unwrap().to_str().unwrap().to_string();
-        let doc_root_path = Path::new(&doc_root).parent().unwrap();
-        // println!("doc_root_path: {:?}", doc_root_path);
+        let doc_root = resolve_loc("", doc_body_v
+            .get_child("DocRoot")
+            .expect("DocRoot not found")
+            .get_text()
+            .unwrap()
+            .as_ref());
+        self.doc_root = loc_dir(&doc_root).to_string();
+        let doc_dir = self.doc_root.clone();
+        let signatures = doc_body_v.get_child("Signatures")
+            .and_then(|e| e.get_text())
+            .map(|loc| resolve_loc("", &loc));
 
         let mut file = self.archive.by_name(&doc_root).unwrap();
         let mut s = String::new();
@@ -92,54 +101,74 @@ impl OFDFile {
         // println!("ofd_document: {:?}", ofd_document);
 
         self.ofd_doc = Some(OFDDoc {
-            doc_root_path: doc_root_path.to_str().unwrap().to_string(),
+            doc_root_path: doc_dir.clone(),
             template_pages: ofd_document.common_data.template_page.map(
                 |tp| tp.into_iter().map(|s|
                 TemplatePage {
                     id: s.id,
-                    base_loc: format!("{}/{}", doc_root_path.to_str().unwrap(), s.base_loc),
+                    base_loc: resolve_loc(&doc_dir, &s.base_loc),
+                    z_order: s.z_order,
                 }
             ).collect()),
-            physical_box: ofd_document.common_data.page_area.map_or(None, |s| {
-                Some(s.physical_box)
+            page_area: ofd_document.common_data.page_area,
+            default_cs: ofd_document.common_data.default_cs,
+            document_res: OFDRes::load_all(&mut self.archive, &doc_dir, &ofd_document.common_data.document_res),
+            public_res: OFDRes::load_all(&mut self.archive, &doc_dir, &ofd_document.common_data.public_res),
+            pages: OFDPage::load_all(&mut self.archive, &doc_dir, ofd_document.pages.page),
+            annotations: ofd_document.annotations.and_then(|loc| {
+                OFDAnnotations::load(&mut self.archive, &resolve_loc(&doc_dir, &loc))
+                    .map_err(|e| log::warn!("{}, the document is drawn without annotations", e))
+                    .ok()
             }),
-            document_res: OFDRes::new(
-                &mut self.archive,
-                format!("{}/{}", doc_root_path.to_str().unwrap(), ofd_document.common_data.document_res)
-            ),
-            public_res: OFDRes::new(
-                &mut self.archive,
-                format!("{}/{}", doc_root_path.to_str().unwrap(), ofd_document.common_data.public_res)
-            ),
-            pages: ofd_document.pages.page.into_iter().enumerate().map(|(idx, page)|
-                OFDPage::new(
-                    &mut self.archive,
-                    format!("{}/{}", doc_root_path.to_str().unwrap(), page.base_loc).as_str(),
-                    idx,
-                    page.id,
-                )
-            ).collect(),
-            annotations: OFDAnnotations::new(
-                &mut self.archive,
-                format!("{}/{}", doc_root_path.to_str().unwrap(), ofd_document.annotations)
-            ),
-            attachment: ofd_document.attachments,
+            attachment: ofd_document.attachments.map(|loc| resolve_loc(&doc_dir, &loc)),
+            seals: signatures.map_or_else(Vec::new, |path| load_seals(&mut self.archive, &path)),
         });
         // println!("ofd_doc: {:?}", self.ofd_doc);
     }
 
     fn parse_resources(&mut self) {
-        let ofd_doc = self.ofd_doc.as_mut().unwrap();
-        for multimedia in &ofd_doc.document_res.multi_medias {
+        let ofd_doc = self.ofd_doc.as_ref().unwrap();
+        // document resources shadow public ones
+        let resources: Vec<&OFDRes> = ofd_doc.public_res.iter().chain(&ofd_doc.document_res).collect();
+        register_resources(&mut self.archive, &resources, ofd_doc.default_cs.as_deref());
+    }
+
+    pub fn draw(&mut self) {
+        let ofd_doc = self.ofd_doc.clone().expect("ofd_doc is None");
+        ofd_doc.draw_pages(&mut self.archive, self.file_name.as_str(), self.render_options);
+    }
+
+    /// Draws image objects with their `Substitution` image, if they have one.
+    pub fn use_image_substitutions(&mut self, enable: bool) {
+        self.render_options.use_image_substitutions = enable;
+    }
+
+    /// Draws the pages clipped to their `page_box`.
+    pub fn use_page_box(&mut self, page_box: PageBox) {
+        self.render_options.page_box = page_box;
+    }
+}
+
+/// Registers the resources of resource files, shadowing those registered before with the
+/// same ID and those of the files before them, and decodes their images.
+///
+/// `Relative` references of DrawParams are resolved once all of the files are registered,
+/// so a DrawParam may inherit from one of any of the files.
+fn register_resources<R: Read + Seek>(archive: &mut ZipArchive<R>, resources: &[&OFDRes], default_cs: Option<&str>) {
+    let mut media_files = Vec::new();
+    for res in resources {
+        for multimedia in &res.multi_medias {
             if multimedia.type_.eq("Image") {
+                let path = res.file_path(&multimedia.media_file.text);
                 MUTEX_IMAGE_RES.lock().unwrap().insert(
                     multimedia.id.clone(),
-                    multimedia.media_file.text.clone()
+                    path.clone()
                 );
+                media_files.push((path, multimedia.format.as_deref()));
             }
         }
 
-        for font in &ofd_doc.public_res.fonts {
+        for font in &res.fonts {
             let family_name = font.family_name.clone().unwrap_or(font.font_name.clone());
             RES_FONT_ID_MAP.lock().unwrap().insert(
                 font.id.clone(),
@@ -152,97 +181,97 @@ impl OFDFile {
         }
         // println!("RES_FONT_ID_MAP: {:?}", RES_FONT_ID_MAP.lock().unwrap());
 
-        let mut hashmap = HashMap::new();
-        for draw_param in &ofd_doc.public_res.draw_params {
-            MUTEX_RES_DRAW_PARAMS.lock().unwrap().insert(
-                draw_param.id.clone(),
-                draw_param.clone()
+        for color_space in &res.color_spaces {
+            MUTEX_RES_COLOR_SPACES.lock().unwrap().insert(
+                color_space.id.clone(),
+                color_space.clone()
             );
-            hashmap.insert(draw_param.id.clone(), draw_param);
+            if let Some(profile) = &color_space.profile {
+                let data = archive.by_name(&res.file_path(profile)).ok().and_then(|mut file| {
+                    let mut buf: Vec<u8> = Vec::new();
+                    file.read_to_end(&mut buf).ok().map(|_| buf)
+                });
+                match data {
+                    Some(data) => if !icc::register_profile(&color_space.id, color_space.type_, data) {
+                        log::warn!("unsupported ICC profile {} of ColorSpace {}", profile, color_space.id);
+                    },
+                    None => log::warn!("ICC profile {} of ColorSpace {} not found", profile, color_space.id),
+                }
+            }
         }
-        for draw_param in &ofd_doc.document_res.draw_params {
-            MUTEX_RES_DRAW_PARAMS.lock().unwrap().insert(
-                draw_param.id.clone(),
-                draw_param.clone()
+        for unit in &res.composite_graphic_units {
+            MUTEX_RES_COMPOSITE_GRAPHIC_UNITS.lock().unwrap().insert(
+                unit.id.clone(),
+                unit.clone()
             );
-            hashmap.insert(draw_param.id.clone(), draw_param);
         }
-        for draw_param in MUTEX_RES_DRAW_PARAMS.lock().unwrap().values_mut() {
-            if let Some(relative) = draw_param.relative.clone() {
-                let relative_draw_param = hashmap.get(&relative).unwrap().clone();
-                draw_param.update(&relative_draw_param);
+    }
+
+    // parents may be registered already, they are resolved themselves
+    let mut hashmap = MUTEX_RES_DRAW_PARAMS.lock().unwrap().visible();
+    let draw_params: Vec<&DrawParam> = resources.iter().flat_map(|res| &res.draw_params).collect();
+    for draw_param in &draw_params {
+        hashmap.insert(draw_param.id.clone(), (*draw_param).clone());
+    }
+    for draw_param in &draw_params {
+        let resolved = resolve_draw_param(draw_param, &hashmap).unwrap_or_else(|e| {
+            log::warn!("{}, using DrawParam {} without inheritance", e, draw_param.id);
+            (*draw_param).clone()
+        });
+        MUTEX_RES_DRAW_PARAMS.lock().unwrap().insert(
+            draw_param.id.clone(),
+            resolved
+        );
+    }
+    // println!("MUTEX_RES_DRAW_PARAMS: {:?}", MUTEX_RES_DRAW_PARAMS.lock().unwrap());
+
+    let default_profile = default_cs.and_then(icc::get_profile);
+    for (v, format) in &media_files {
+        let mut file = match archive.by_name(v) {
+            Ok(file) => file,
+            Err(e) => {
+                log::warn!("image {} not loaded: {}", v, e);
+                continue;
             }
+        };
+        let mut buf: Vec<u8> = Vec::new();
+        if let Err(e) = file.read_to_end(&mut buf) {
+            log::warn!("image {} not loaded: {}", v, e);
+            continue;
         }
-        // println!("MUTEX_RES_DRAW_PARAMS: {:?}", MUTEX_RES_DRAW_PARAMS.lock().unwrap());
-
-        for i in 0..self.archive.len() {
-            let mut file = self.archive.by_index(i).unwrap();
-            for v in MUTEX_IMAGE_RES.lock().unwrap().values() {
-                if (*file.name()).ends_with(v) {
-                    let mut buf: Vec<u8> = Vec::new();
-                    let _ = &file.read_to_end(&mut buf).unwrap();
-                    if (*file.name()).ends_with(".png") {
-                        let dyn_image = image::load_from_memory(&buf)
-                            .expect("convert to DynamicImage failed")
-                            .into_rgba8();
-                        MUTEX_IMAGE_PNG_RES
-                            .lock()
-                            .unwrap()
-                            .insert(String::from(v), buf);
-                        MUTEX_RGB_IMAGE_RES
-                            .lock()
-                            .unwrap()
-                            .insert(String::from(v), dyn_image);
-                    } else if (*file.name()).ends_with(".jpg") || (*file.name()).ends_with(".jpeg") {
-                        let dyn_image = image::load_from_memory(&buf)
-                            .expect("convert to DynamicImage failed")
-                            .into_rgba8();
-                        MUTEX_IMAGE_PNG_RES
-                            .lock()
-                            .unwrap()
-                            .insert(String::from(v), buf);
-                        MUTEX_RGB_IMAGE_RES
-                            .lock()
-                            .unwrap()
-                            .insert(String::from(v), dyn_image);
-                    } else if (*file.name()).ends_with(".jb2") {
-                        let mut buff = Cursor::new(buf);
-                        let doc = Document::from_reader(&mut buff).expect("");
-                        let image = doc.images().get(0).unwrap().clone();
-                        let img = image.to_png().unwrap();
-                        let dyn_image = image::load_from_memory(&img)
-                            .expect("convert to DynamicImage failed")
-                            .into_rgba8();
-                        MUTEX_RGB_IMAGE_RES
-                            .lock()
-                            .unwrap()
-                            .insert(String::from(v), dyn_image);
-                        MUTEX_IMAGE_PNG_RES
-                            .lock()
-                            .unwrap()
-                            .insert(String::from(v), img);
-                    }
-                }
+        let decoded = match ImageFormat::detect(&buf, *format, v) {
+            Some(image_format) => images::decode(&buf, image_format, default_profile.as_deref()),
+            None => {
+                log::warn!("image {} of unknown format {:?}", v, format);
+                continue;
+            }
+        };
+        match decoded {
+            Some(decoded) => {
+                MUTEX_STENCIL_IMAGES.lock().unwrap().insert(v.clone(), decoded.stencil);
+                MUTEX_RGB_IMAGE_RES
+                    .lock()
+                    .unwrap()
+                    .insert(v.clone(), decoded.pixels);
             }
+            None => log::warn!("image {} not decoded", v),
         }
     }
-
-    pub fn draw(&mut self) {
-        let ofd_doc = self.ofd_doc.clone().expect("ofd_doc is None");
-        ofd_doc.draw_pages(&mut self.archive, self.file_name.as_str());
-    }
 }
 
 #[derive(Debug, Clone)]
 pub struct OFDDoc {
     pub doc_root_path: String,
     pub template_pages: Option<Vec<TemplatePage>>,
-    pub physical_box: Option<PhysicalBox>,
-    pub document_res: OFDRes,
-    pub public_res: OFDRes,
+    pub page_area: Option<PageArea>,
+    pub default_cs: Option<String>,
+    pub document_res: Vec<OFDRes>,
+    pub public_res: Vec<OFDRes>,
     pub pages: Vec<OFDPage>,
-    pub annotations: OFDAnnotations,
+    pub annotations: Option<OFDAnnotations>,
+    /// Archive path of the attachments list.
     pub attachment: Option<String>,
+    pub seals: Vec<Seal>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -263,17 +292,10 @@ struct OFDAnnotation {
 }
 
 impl OFDAnnotations {
-    fn new(archive: &mut ZipArchive<File>, path: String) -> Self {
-        // get dir from path
-        let dir = Path::new(path.as_str()).parent().unwrap().to_str().unwrap();
-
-        let mut file = archive.by_name(path.as_str()).unwrap();
-        let mut buf: Vec<u8> = Vec::new();
-        let _ = &file.read_to_end(&mut buf).unwrap();
-        let mut buf = Cursor::new(buf);
-        let mut annotations: OFDAnnotations = quick_xml::de::from_reader(&mut buf).unwrap();
-        annotations.dir = dir.to_string();
-        annotations
+    fn load<R: Read + Seek>(archive: &mut ZipArchive<R>, path: &str) -> Result<Self, OFDError> {
+        let mut annotations: OFDAnnotations = read_xml(archive, path)?;
+        annotations.dir = loc_dir(path).to_string();
+        Ok(annotations)
     }
 }
 
@@ -306,24 +328,16 @@ pub struct Appearance {
     #[serde(deserialize_with = "deserialize_physical_box")]
     pub boundary: PhysicalBox,
 
-    image_object: Option<Vec<ImageObject>>,
-    text_object: Option<Vec<TextObject>>,
+    #[serde(rename = "$value", default)]
+    units: Vec<GraphicUnit>,
 }
 
 impl Appearance {
     fn draw(&self, backend: &mut dyn DrawBackend) {
         let transform = backend.save();
         backend.draw_boundary(&self.boundary);
-
-        if let Some(image_object) = &self.image_object {
-            for image_object in image_object {
-                backend.draw_image_object(&image_object);
-            }
-        }
-        if let Some(text_object) = &self.text_object {
-            for text_object in text_object {
-                backend.draw_text_object(None, &text_object);
-            }
+        for unit in &self.units {
+            unit.draw(backend, None);
         }
         backend.restore(&transform);
     }
@@ -334,14 +348,197 @@ impl Appearance {
 pub struct _Color {
     #[serde(rename = "@Value")]
     pub value: Option<String>,
+    #[serde(rename = "@Index")]
+    pub index: Option<usize>,
     #[serde(rename = "@ColorSpace")]
     pub color_space: Option<String>,
+    #[serde(rename = "@Alpha", default = "default_alpha")]
+    pub alpha: u8,
+
+    pub pattern: Option<Box<Pattern>>,
+    pub axial_shd: Option<Box<AxialShd>>,
+    pub radial_shd: Option<Box<RadialShd>>,
+    pub gouraud_shd: Option<Box<GouraudShd>>,
+    pub la_gouraud_shd: Option<Box<LaGouraudShd>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+pub enum MapType {
+    #[default]
+    Direct,
+    Repeat,
+    Reflect,
+}
+
+/// A colour of a gradient at `Position` (0.0 - 1.0) along its axis.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Segment {
+    #[serde(rename = "@Position")]
+    pub position: Option<f32>,
+    pub color: _Color,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AxialShd {
+    #[serde(rename = "@MapType", default)]
+    pub map_type: MapType,
+    #[serde(rename = "@MapUnit")]
+    pub map_unit: Option<f32>,
+    /// 0: no extension, 1: beyond the start point, 2: beyond the end point, 3: both.
+    #[serde(rename = "@Extend", default)]
+    pub extend: u8,
+    #[serde(rename = "@StartPoint", deserialize_with = "deserialize_pos")]
+    pub start_point: (f32, f32),
+    #[serde(rename = "@EndPoint", deserialize_with = "deserialize_pos")]
+    pub end_point: (f32, f32),
+    #[serde(default)]
+    pub segment: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct RadialShd {
+    #[serde(rename = "@MapType", default)]
+    pub map_type: MapType,
+    #[serde(rename = "@MapUnit")]
+    pub map_unit: Option<f32>,
+    #[serde(rename = "@Eccentricity", default)]
+    pub eccentricity: f32,
+    /// Rotation of the ellipses' major axis, in degrees.
+    #[serde(rename = "@Angle", default)]
+    pub angle: f32,
+    #[serde(rename = "@StartPoint", deserialize_with = "deserialize_pos")]
+    pub start_point: (f32, f32),
+    #[serde(rename = "@StartRadius", default)]
+    pub start_radius: f32,
+    #[serde(rename = "@EndPoint", deserialize_with = "deserialize_pos")]
+    pub end_point: (f32, f32),
+    #[serde(rename = "@EndRadius")]
+    pub end_radius: f32,
+    #[serde(rename = "@Extend", default)]
+    pub extend: u8,
+    #[serde(default)]
+    pub segment: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct GouraudPoint {
+    #[serde(rename = "@X")]
+    pub x: f32,
+    #[serde(rename = "@Y")]
+    pub y: f32,
+    /// How the point joins the previous triangle, only used by `GouraudShd`.
+    #[serde(rename = "@EdgeFlag")]
+    pub edge_flag: Option<u8>,
+    pub color: _Color,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct GouraudShd {
+    #[serde(rename = "@Extend", default)]
+    pub extend: u8,
+    #[serde(default)]
+    pub point: Vec<GouraudPoint>,
+    pub back_color: Option<_Color>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LaGouraudShd {
+    #[serde(rename = "@VerticesPerRow")]
+    pub vertices_per_row: usize,
+    #[serde(rename = "@Extend", default)]
+    pub extend: u8,
+    #[serde(default)]
+    pub point: Vec<GouraudPoint>,
+    pub back_color: Option<_Color>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+pub enum ReflectMethod {
+    #[default]
+    Normal,
+    Row,
+    Column,
+    RowAndColumn,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+pub enum RelativeTo {
+    Page,
+    #[default]
+    Object,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Pattern {
+    #[serde(rename = "@Width")]
+    pub width: f32,
+    #[serde(rename = "@Height")]
+    pub height: f32,
+    #[serde(rename = "@XStep")]
+    pub x_step: Option<f32>,
+    #[serde(rename = "@YStep")]
+    pub y_step: Option<f32>,
+    #[serde(rename = "@ReflectMethod", default)]
+    pub reflect_method: ReflectMethod,
+    #[serde(rename = "@RelativeTo", default)]
+    pub relative_to: RelativeTo,
+    #[serde(rename = "@CTM")]
+    pub ctm: Option<String>,
+    /// The page block drawn in each cell, shared by the clones of the pattern.
+    #[serde(deserialize_with = "deserialize_shared")]
+    pub cell_content: Arc<PageBlock>,
+}
+
+fn deserialize_shared<'de, D, T>(deserializer: D) -> Result<Arc<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Arc::new)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum ColorSpaceType {
+    GRAY,
+    RGB,
+    CMYK,
+}
+
+fn default_bits_per_component() -> u8 {
+    8
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ColorSpace {
+    #[serde(rename = "@ID")]
+    pub id: String,
+    #[serde(rename = "@Type")]
+    pub type_: ColorSpaceType,
+    #[serde(rename = "@BitsPerComponent", default = "default_bits_per_component")]
+    pub bits_per_component: u8,
+    #[serde(rename = "@Profile")]
+    pub profile: Option<String>,
+
+    /// Colour values of an indexed colour space, referred by `_Color@Index`.
+    #[serde(default, deserialize_with = "deserialize_unwrap_palette")]
+    pub palette: Vec<String>,
 }
 
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct OFDRes {
+    /// Directory of the resource file in the archive.
+    #[serde(skip_deserializing)]
+    pub dir: String,
     #[serde(rename = "@BaseLoc")]
     pub base_loc: Option<String>,
 
@@ -351,6 +548,28 @@ pub struct OFDRes {
     pub draw_params: Vec<DrawParam>,
     #[serde(default, deserialize_with = "deserialize_unwrap_fonts")]
     pub fonts: Vec<_Font>,
+    #[serde(default, deserialize_with = "deserialize_unwrap_color_spaces")]
+    pub color_spaces: Vec<ColorSpace>,
+    #[serde(default, deserialize_with = "deserialize_unwrap_composite_graphic_units")]
+    pub composite_graphic_units: Vec<VectorG>,
+}
+
+/// A reusable vector graphic of `Width` by `Height`, drawn by `CompositeObject`s.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct VectorG {
+    #[serde(rename = "@ID")]
+    pub id: String,
+    #[serde(rename = "@Width")]
+    pub width: f32,
+    #[serde(rename = "@Height")]
+    pub height: f32,
+
+    /// Image resource previewing the unit.
+    pub thumbnail: Option<String>,
+    /// Unit drawn in place of this one by viewers that can't draw its content.
+    pub substitution: Option<String>,
+    pub content: PageBlock,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -364,11 +583,34 @@ pub struct _Font {
 }
 
 impl OFDRes {
-    fn new(archive: &mut ZipArchive<File>, path: String) -> OFDRes {
-        let mut z_f = archive.by_name(path.as_str()).unwrap();
+    /// Loads the resource files at `locs` relative to `dir`, those that fail are reported
+    /// and skipped.
+    fn load_all<R: Read + Seek>(archive: &mut ZipArchive<R>, dir: &str, locs: &[String]) -> Vec<OFDRes> {
+        locs.iter()
+            .filter_map(|loc| {
+                let path = resolve_loc(dir, loc);
+                OFDRes::load(archive, &path)
+                    .map_err(|e| log::warn!("{}, the resources of {} are skipped", e, path))
+                    .ok()
+            })
+            .collect()
+    }
+
+    fn load<R: Read + Seek>(archive: &mut ZipArchive<R>, path: &str) -> Result<OFDRes, OFDError> {
+        let mut z_f = archive.by_name(path).map_err(OFDError::ZipError)?;
         let mut s = String::new();
-        let _ = &z_f.read_to_string(&mut s).unwrap();
-        quick_xml::de::from_str(&s).unwrap()
+        z_f.read_to_string(&mut s)
+            .map_err(|e| OFDError::CustomError(format!("read {} failed: {}", path, e)))?;
+        let mut res: OFDRes = quick_xml::de::from_str(&s)
+            .map_err(|e| OFDError::CustomError(format!("parse {} failed: {}", path, e)))?;
+        res.dir = loc_dir(path).to_string();
+        Ok(res)
+    }
+
+    /// The archive path of a file of the resources, relative to their `BaseLoc`.
+    fn file_path(&self, loc: &str) -> String {
+        let base = resolve_loc(&self.dir, self.base_loc.as_deref().unwrap_or_default());
+        resolve_loc(&base, loc)
     }
 }
 
@@ -411,6 +653,57 @@ where
     Ok(Fonts::deserialize(deserializer)?.font)
 }
 
+fn deserialize_unwrap_color_spaces<'de, D>(deserializer: D) -> Result<Vec<ColorSpace>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct ColorSpaces {
+        #[serde(default)]
+        color_space: Vec<ColorSpace>,
+    }
+    Ok(ColorSpaces::deserialize(deserializer)?.color_space)
+}
+
+fn deserialize_unwrap_composite_graphic_units<'de, D>(deserializer: D) -> Result<Vec<VectorG>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct CompositeGraphicUnits {
+        #[serde(default)]
+        composite_graphic_unit: Vec<VectorG>,
+    }
+    Ok(CompositeGraphicUnits::deserialize(deserializer)?.composite_graphic_unit)
+}
+
+fn deserialize_unwrap_clips<'de, D>(deserializer: D) -> Result<Vec<Clip>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct Clips {
+        #[serde(default)]
+        clip: Vec<Clip>,
+    }
+    Ok(Clips::deserialize(deserializer)?.clip)
+}
+
+fn deserialize_unwrap_palette<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Palette {
+        #[serde(default, rename = "CV")]
+        cv: Vec<String>,
+    }
+    Ok(Palette::deserialize(deserializer)?.cv)
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 struct OFDMultiMedias {
@@ -443,34 +736,120 @@ struct FileLoc {
 }
 
 
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum LineJoin {
+    Miter,
+    Round,
+    Bevel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum LineCap {
+    Butt,
+    Round,
+    Square,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+pub enum FillRule {
+    #[default]
+    NonZero,
+    #[serde(rename = "Even-Odd")]
+    EvenOdd,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_alpha() -> u8 {
+    255
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct DrawParam {
     #[serde(rename = "@ID")]
     id: String,
     #[serde(rename = "@LineWidth")]
-    line_width: Option<f32>,
+    pub line_width: Option<f32>,
     #[serde(rename="@Relative")]
     pub relative: Option<String>,
+    #[serde(rename = "@Join")]
+    pub join: Option<LineJoin>,
+    #[serde(rename = "@Cap")]
+    pub cap: Option<LineCap>,
+    #[serde(rename = "@DashOffset")]
+    pub dash_offset: Option<f32>,
+    #[serde(rename = "@DashPattern", default, deserialize_with = "deserialize_f32_array")]
+    pub dash_pattern: Option<Vec<f32>>,
+    #[serde(rename = "@MiterLimit")]
+    pub miter_limit: Option<f32>,
 
     pub fill_color: Option<_Color>,
     pub stroke_color: Option<_Color>,
 }
 
 impl DrawParam {
-    pub fn update(&mut self, draw_param: &DrawParam) {
-        if draw_param.line_width.is_some() {
-            self.line_width = draw_param.line_width;
+    /// Fills the attributes this draw param doesn't specify from `parent`,
+    /// attributes set on `self` always take precedence.
+    pub fn inherit(&mut self, parent: &DrawParam) {
+        if self.line_width.is_none() {
+            self.line_width = parent.line_width;
+        }
+        if self.join.is_none() {
+            self.join = parent.join;
+        }
+        if self.cap.is_none() {
+            self.cap = parent.cap;
+        }
+        if self.dash_offset.is_none() {
+            self.dash_offset = parent.dash_offset;
+        }
+        if self.dash_pattern.is_none() {
+            self.dash_pattern = parent.dash_pattern.clone();
         }
-        if draw_param.fill_color.is_some() {
-            self.fill_color = draw_param.fill_color.clone();
+        if self.miter_limit.is_none() {
+            self.miter_limit = parent.miter_limit;
         }
-        if draw_param.stroke_color.is_some() {
-            self.stroke_color = draw_param.stroke_color.clone();
+        if self.fill_color.is_none() {
+            self.fill_color = parent.fill_color.clone();
+        }
+        if self.stroke_color.is_none() {
+            self.stroke_color = parent.stroke_color.clone();
         }
     }
 }
 
+/// Resolves the whole `Relative` chain of `draw_param` (A -> B -> C ...), the nearer
+/// draw param in the chain wins over the farther ones.
+///
+/// Fails on a `Relative` id missing from `draw_params` or on a chain referring back to itself.
+pub fn resolve_draw_param(draw_param: &DrawParam, draw_params: &HashMap<String, DrawParam>)
+    -> Result<DrawParam, OFDError>
+{
+    let mut resolved = draw_param.clone();
+    let mut chain = vec![draw_param.id.clone()];
+    let mut relative = draw_param.relative.clone();
+    while let Some(relative_id) = relative {
+        if chain.contains(&relative_id) {
+            chain.push(relative_id);
+            return Err(OFDError::DrawParamCycle(chain));
+        }
+        let parent = draw_params.get(&relative_id).ok_or_else(|| {
+            OFDError::ResourceNotFound(format!(
+                "DrawParam {} referenced by Relative of DrawParam {}",
+                relative_id,
+                chain.last().unwrap()
+            ))
+        })?;
+        resolved.inherit(parent);
+        chain.push(relative_id);
+        relative = parent.relative.clone();
+    }
+    Ok(resolved)
+}
+
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "PascalCase")]
@@ -479,9 +858,19 @@ pub struct OFDPage {
     idx: usize,
     #[serde(skip_deserializing)]
     id: String,
+    /// Directory of the page's `Content.xml`, `PageRes` are relative to it.
+    #[serde(skip_deserializing)]
+    dir: String,
 
-    template: Option<_PageTemplate>,
-    area: Option<Area>,
+    #[serde(default)]
+    template: Vec<_PageTemplate>,
+    /// Resource files of this page only.
+    #[serde(default)]
+    page_res: Vec<String>,
+    /// The loaded `page_res`, registered while the page is drawn.
+    #[serde(skip_deserializing)]
+    resources: Vec<OFDRes>,
+    area: Option<PageArea>,
     content: OFDContent,
 }
 
@@ -489,7 +878,7 @@ pub struct OFDPage {
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct ContentPage {
-    area: Option<Area>,
+    area: Option<PageArea>,
     content: OFDContent,
 }
 
@@ -507,51 +896,97 @@ impl OFDContent {
     }
 }
 
+/// Orders the layers of a page and of its templates for painting: by layer `Type`,
+/// then background templates, the page itself and foreground templates.
+fn stack_layers<'a>(page: &'a OFDContent, templates: &[(ZOrder, &'a OFDContent)]) -> Vec<&'a OFDLayer> {
+    let background = templates.iter().filter(|(z_order, _)| *z_order == ZOrder::Background);
+    let foreground = templates.iter().filter(|(z_order, _)| *z_order == ZOrder::Foreground);
+    let mut layers: Vec<&OFDLayer> = background.map(|(_, content)| *content)
+        .chain(std::iter::once(page))
+        .chain(foreground.map(|(_, content)| *content))
+        .flat_map(|content| content.layers.iter())
+        .collect();
+    // stable, so sources keep their order within a type
+    layers.sort_by_key(|layer| layer.type_);
+    layers
+}
+
+/// The stacking of a layer among the page's layers, `Body` if unspecified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Deserialize)]
+pub enum LayerType {
+    Background,
+    #[default]
+    Body,
+    Foreground,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct OFDLayer {
     #[serde(rename = "@ID")]
     id: String,
+    #[serde(rename = "@Type", default)]
+    type_: LayerType,
     #[serde(rename = "@DrawParam")]
     draw_param_id: Option<String>,
 
-    path_object: Option<Vec<PathObject>>,
-    image_object: Option<Vec<ImageObject>>,
-    text_object: Option<Vec<TextObject>>,
+    #[serde(rename = "$value", default)]
+    units: Vec<GraphicUnit>,
+}
+
 
-    page_block: Option<Vec<PageBlock>>,
+/// A graphic unit of page content. Units are kept in document order, which is the
+/// order they are painted in.
+#[derive(Debug, Clone, Deserialize)]
+pub enum GraphicUnit {
+    PathObject(PathObject),
+    TextObject(TextObject),
+    ImageObject(ImageObject),
+    CompositeObject(CompositeObject),
+    PageBlock(PageBlock),
+    /// Anything else, skipped.
+    #[serde(other)]
+    Unknown,
 }
 
+impl GraphicUnit {
+    pub fn draw(&self, backend: &mut dyn DrawBackend, draw_param_id: Option<&String>) {
+        match self {
+            GraphicUnit::PathObject(path_object) => path_object.draw(backend, draw_param_id),
+            GraphicUnit::TextObject(text_object) => text_object.draw(backend, draw_param_id),
+            GraphicUnit::ImageObject(image_object) => image_object.draw(backend, draw_param_id),
+            GraphicUnit::CompositeObject(composite_object) => composite_object.draw(backend, draw_param_id),
+            GraphicUnit::PageBlock(page_block) => page_block.draw(backend, draw_param_id.cloned()),
+            GraphicUnit::Unknown => {}
+        }
+    }
+}
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct PageBlock {
+    /// `None` for the `CellContent` of a `Pattern`.
     #[serde(rename = "@ID")]
-    id: String,
+    id: Option<String>,
+    /// Opacity of the block as a whole.
+    #[serde(rename = "@Alpha", default = "default_alpha")]
+    alpha: u8,
 
-    path_object: Option<Vec<PathObject>>,
-    image_object: Option<Vec<ImageObject>>,
-    text_object: Option<Vec<TextObject>>,
+    #[serde(rename = "$value", default)]
+    units: Vec<GraphicUnit>,
 }
 
 impl PageBlock {
-    fn draw(&self, backend: &mut dyn DrawBackend, draw_param_id: Option<String>) {
+    pub fn draw(&self, backend: &mut dyn DrawBackend, draw_param_id: Option<String>) {
         // println!("draw PageBlock: {:?}", self);
-        if let Some(path_objects) = &self.path_object {
-            for path_object in path_objects {
-                backend.draw_path_object(draw_param_id.as_ref(), &path_object);
-            }
+        if self.alpha < 255 {
+            backend.begin_group(self.alpha);
         }
-        if let Some(text_objects) = &self.text_object {
-            for text_object in text_objects {
-                backend.draw_text_object(draw_param_id.as_ref(), &text_object);
-            }
+        for unit in &self.units {
+            unit.draw(backend, draw_param_id.as_ref());
         }
-
-        if let Some(image_objects) = &self.image_object {
-            for image_object in image_objects {
-                backend.draw_image_object(&image_object);
-            }
+        if self.alpha < 255 {
+            backend.end_group();
         }
     }
 }
@@ -562,27 +997,8 @@ impl OFDLayer {
         let transform = backend.save();
         // println!("draw layer with draw_param: {:?}, transform: {:?}", self.draw_param_id, transform);
         // backend.scale();
-        if let Some(path_objects) = &self.path_object {
-            for path_object in path_objects {
-                backend.draw_path_object(self.draw_param_id.as_ref(), &path_object);
-            }
-        }
-        if let Some(text_objects) = &self.text_object {
-            for text_object in text_objects {
-                backend.draw_text_object(self.draw_param_id.as_ref(), &text_object);
-            }
-        }
-
-        if let Some(image_objects) = &self.image_object {
-            for image_object in image_objects {
-                backend.draw_image_object(&image_object);
-            }
-        }
-
-        if let Some(page_blocks) = &self.page_block {
-            for page_block in page_blocks {
-                page_block.draw(backend, self.draw_param_id.clone());
-            }
+        for unit in &self.units {
+            unit.draw(backend, self.draw_param_id.as_ref());
         }
         backend.restore(&transform);
     }
@@ -592,8 +1008,11 @@ impl OFDLayer {
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct PathObject {
-    #[serde(rename = "@ID")]
+    /// Empty for the `Path` of a clip `Area`.
+    #[serde(rename = "@ID", default)]
     id: String,
+    #[serde(rename = "@DrawParam")]
+    pub draw_param: Option<String>,
     #[serde(rename = "@Boundary")]
     #[serde(deserialize_with = "deserialize_physical_box")]
     pub boundary: PhysicalBox,
@@ -601,32 +1020,77 @@ pub struct PathObject {
     pub line_width: Option<f32>,
     #[serde(rename="@CTM")]
     pub ctm: Option<String>,
+    #[serde(rename = "@Alpha", default = "default_alpha")]
+    pub alpha: u8,
+    #[serde(rename = "@Join")]
+    pub join: Option<LineJoin>,
+    #[serde(rename = "@Cap")]
+    pub cap: Option<LineCap>,
+    #[serde(rename = "@DashOffset")]
+    pub dash_offset: Option<f32>,
+    #[serde(rename = "@DashPattern", default, deserialize_with = "deserialize_f32_array")]
+    pub dash_pattern: Option<Vec<f32>>,
+    #[serde(rename = "@MiterLimit")]
+    pub miter_limit: Option<f32>,
+    #[serde(rename = "@Stroke", default = "default_true")]
+    pub stroke: bool,
+    #[serde(rename = "@Fill", default)]
+    pub fill: bool,
+    #[serde(rename = "@Rule", default)]
+    pub rule: FillRule,
 
     pub stroke_color: Option<_Color>,
     pub fill_color: Option<_Color>,
+    #[serde(default, deserialize_with = "deserialize_unwrap_clips")]
+    pub clips: Vec<Clip>,
     pub abbreviated_data: String,
 }
 
+impl PathObject {
+    fn draw(&self, backend: &mut dyn DrawBackend, layer_draw_param_id: Option<&String>) {
+        draw_clipped(backend, &self.clips, &self.boundary, |backend| {
+            backend.draw_path(&self.resolve(layer_draw_param_id));
+        });
+    }
+}
+
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct TextObject {
-    #[serde(rename = "@ID")]
+    /// Empty for the `Text` of a clip `Area`.
+    #[serde(rename = "@ID", default)]
     id: String,
+    #[serde(rename = "@DrawParam")]
+    pub draw_param: Option<String>,
     #[serde(rename = "@Boundary")]
     #[serde(deserialize_with = "deserialize_physical_box")]
     pub boundary: PhysicalBox,
     #[serde(rename = "@CTM")]
     pub ctm: Option<String>,
+    #[serde(rename = "@Alpha", default = "default_alpha")]
+    pub alpha: u8,
     #[serde(rename = "@Font")]
     pub font: String,
     #[serde(rename = "@Size")]
     pub size: f32,
+    #[serde(rename = "@Fill", default = "default_true")]
+    pub fill: bool,
 
     pub fill_color: Option<_Color>,
     pub stroke_color: Option<_Color>,
+    #[serde(default, deserialize_with = "deserialize_unwrap_clips")]
+    pub clips: Vec<Clip>,
     pub text_code: TextCode,
 }
+impl TextObject {
+    fn draw(&self, backend: &mut dyn DrawBackend, layer_draw_param_id: Option<&String>) {
+        draw_clipped(backend, &self.clips, &self.boundary, |backend| {
+            backend.draw_text(&self.resolve(layer_draw_param_id));
+        });
+    }
+}
+
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "PascalCase")]
@@ -653,11 +1117,223 @@ pub struct ImageObject {
     pub resource_id: String,
     #[serde(rename = "@ID")]
     pub id: String,
+    #[serde(rename = "@DrawParam")]
+    pub draw_param: Option<String>,
+    #[serde(rename = "@Boundary")]
+    #[serde(deserialize_with = "deserialize_physical_box")]
+    pub boundary: PhysicalBox,
+    #[serde(rename = "@CTM")]
+    pub ctm: Option<String>,
+    #[serde(rename = "@Alpha", default = "default_alpha")]
+    pub alpha: u8,
+    /// Image resource whose luminance is the alpha of the image.
+    #[serde(rename = "@ImageMask")]
+    pub image_mask: Option<String>,
+    /// Alternate image resource, usually of a higher resolution.
+    #[serde(rename = "@Substitution")]
+    pub substitution: Option<String>,
+
+    #[serde(default, deserialize_with = "deserialize_unwrap_clips")]
+    pub clips: Vec<Clip>,
+    pub border: Option<ImageBorder>,
+}
+
+impl ImageObject {
+    fn draw(&self, backend: &mut dyn DrawBackend, layer_draw_param_id: Option<&String>) {
+        draw_clipped(backend, &self.clips, &self.boundary, |backend| {
+            let transform = backend.save();
+            backend.concat(&image_transform(self.ctm.as_ref(), &self.boundary));
+            backend.draw_image_object(self, layer_draw_param_id);
+            backend.restore(&transform);
+            if let Some(border) = &self.border {
+                backend.draw_path(&border.path_object(&self.boundary).resolve(None));
+            }
+        });
+    }
+}
+
+/// The border stroked around the boundary of an image.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ImageBorder {
+    #[serde(rename = "@LineWidth", default = "default_border_width")]
+    pub line_width: f32,
+    /// Spelled `HorizonalCornerRadius` by the standard.
+    #[serde(rename = "@HorizonalCornerRadius", alias = "@HorizontalCornerRadius", default)]
+    pub horizontal_corner_radius: f32,
+    #[serde(rename = "@VerticalCornerRadius", default)]
+    pub vertical_corner_radius: f32,
+    #[serde(rename = "@DashOffset")]
+    pub dash_offset: Option<f32>,
+    #[serde(rename = "@DashPattern", default, deserialize_with = "deserialize_f32_array")]
+    pub dash_pattern: Option<Vec<f32>>,
+    pub border_color: Option<_Color>,
+}
+
+fn default_border_width() -> f32 {
+    0.353
+}
+
+impl ImageBorder {
+    /// The border as a stroked path over `boundary`.
+    fn path_object(&self, boundary: &PhysicalBox) -> PathObject {
+        let (w, h) = (boundary.width, boundary.height);
+        let rx = self.horizontal_corner_radius.clamp(0., w / 2.);
+        let ry = self.vertical_corner_radius.clamp(0., h / 2.);
+        let abbreviated_data = if rx > 0. && ry > 0. {
+            format!(
+                "M {rx} 0 L {} 0 A {rx} {ry} 0 0 1 {w} {ry} L {w} {} A {rx} {ry} 0 0 1 {} {h} \
+                 L {rx} {h} A {rx} {ry} 0 0 1 0 {} L 0 {ry} A {rx} {ry} 0 0 1 {rx} 0 C",
+                w - rx, h - ry, w - rx, h - ry,
+            )
+        } else {
+            format!("M 0 0 L {w} 0 L {w} {h} L 0 {h} C")
+        };
+        PathObject {
+            id: String::new(),
+            draw_param: None,
+            boundary: *boundary,
+            line_width: Some(self.line_width),
+            ctm: None,
+            alpha: default_alpha(),
+            join: None,
+            cap: None,
+            dash_offset: self.dash_offset,
+            dash_pattern: self.dash_pattern.clone(),
+            miter_limit: None,
+            stroke: true,
+            fill: false,
+            rule: FillRule::default(),
+            stroke_color: self.border_color.clone(),
+            fill_color: None,
+            clips: Vec::new(),
+            abbreviated_data,
+        }
+    }
+}
+
+/// Draws the `CompositeGraphicUnit` resource `ResourceID`, its `Content` mapped by the
+/// `CTM` into the `Boundary`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct CompositeObject {
+    #[serde(rename = "@ID", default)]
+    id: String,
+    #[serde(rename = "@ResourceID")]
+    pub resource_id: String,
+    #[serde(rename = "@DrawParam")]
+    pub draw_param: Option<String>,
     #[serde(rename = "@Boundary")]
     #[serde(deserialize_with = "deserialize_physical_box")]
     pub boundary: PhysicalBox,
     #[serde(rename = "@CTM")]
     pub ctm: Option<String>,
+    #[serde(rename = "@Alpha", default = "default_alpha")]
+    pub alpha: u8,
+
+    #[serde(default, deserialize_with = "deserialize_unwrap_clips")]
+    pub clips: Vec<Clip>,
+}
+
+thread_local! {
+    /// Units being drawn by this thread, to stop units that contain themselves.
+    static DRAWING_COMPOSITES: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Marks a composite unit as being drawn until dropped, unwinding included.
+struct DrawingComposite;
+
+impl DrawingComposite {
+    /// `None` if the unit is already being drawn.
+    fn enter(id: &str) -> Option<DrawingComposite> {
+        DRAWING_COMPOSITES.with(|drawing| {
+            let mut drawing = drawing.borrow_mut();
+            if drawing.iter().any(|drawing_id| drawing_id == id) {
+                return None;
+            }
+            drawing.push(id.to_string());
+            Some(DrawingComposite)
+        })
+    }
+}
+
+impl Drop for DrawingComposite {
+    fn drop(&mut self) {
+        DRAWING_COMPOSITES.with(|drawing| drawing.borrow_mut().pop());
+    }
+}
+
+impl CompositeObject {
+    fn draw(&self, backend: &mut dyn DrawBackend, layer_draw_param_id: Option<&String>) {
+        let unit = MUTEX_RES_COMPOSITE_GRAPHIC_UNITS.lock().unwrap().get(&self.resource_id).cloned();
+        let unit = match unit {
+            Some(unit) => unit,
+            None => {
+                log::warn!("CompositeGraphicUnit {} of CompositeObject {} not found", self.resource_id, self.id);
+                return;
+            }
+        };
+        let _drawing = match DrawingComposite::enter(&unit.id) {
+            Some(drawing) => drawing,
+            None => {
+                log::warn!("CompositeGraphicUnit {} contains itself", unit.id);
+                return;
+            }
+        };
+        let draw_param_id = self.draw_param.as_ref().or(layer_draw_param_id).cloned();
+
+        draw_clipped(backend, &self.clips, &self.boundary, |backend| {
+            let transform = backend.save();
+            backend.concat(&object_transform(self.ctm.as_ref(), self.boundary.x, self.boundary.y));
+            if self.alpha < 255 {
+                backend.begin_group(self.alpha);
+            }
+            unit.content.draw(backend, draw_param_id);
+            if self.alpha < 255 {
+                backend.end_group();
+            }
+            backend.restore(&transform);
+        });
+    }
+}
+
+/// Draws a graphic unit inside the intersection of its clips.
+fn draw_clipped<F>(backend: &mut dyn DrawBackend, clips: &[Clip], boundary: &PhysicalBox, draw: F)
+where
+    F: FnOnce(&mut dyn DrawBackend),
+{
+    let clips: Vec<ResolvedClip> = clips.iter()
+        .filter_map(|clip| clip.resolve(boundary))
+        .collect();
+    for clip in &clips {
+        backend.push_clip(clip);
+    }
+    draw(backend);
+    for _ in &clips {
+        backend.pop_clip();
+    }
+}
+
+/// A clip region of a graphic unit, the union of its areas. The clips of a unit
+/// intersect.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Clip {
+    #[serde(default)]
+    pub area: Vec<ClipArea>,
+}
+
+/// A shape of a `Clip`, in the space of the clipped unit's `Boundary`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ClipArea {
+    #[serde(rename = "@DrawParam")]
+    pub draw_param: Option<String>,
+    #[serde(rename = "@CTM")]
+    pub ctm: Option<String>,
+
+    pub path: Option<Box<PathObject>>,
+    pub text: Option<Box<TextObject>>,
 }
 
 
@@ -665,126 +1341,128 @@ pub struct ImageObject {
 struct _PageTemplate {
     #[serde(rename = "@TemplateID")]
     id: String,
+    /// Overrides the `ZOrder` of the template.
+    #[serde(rename = "@ZOrder")]
+    z_order: Option<ZOrder>,
 }
 
-#[derive(Debug, Clone, Copy, Deserialize)]
-#[serde(rename_all = "PascalCase")]
-pub struct Area {
-    #[serde(deserialize_with = "deserialize_physical_box")]
-    physical_box: PhysicalBox,
+/// Whether a template is painted under or over the page content.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+pub enum ZOrder {
+    #[default]
+    Background,
+    Foreground,
 }
 
 impl OFDPage
 {
-    fn new<R: Read + Seek>(archive: &mut ZipArchive<R>, page_path: &str, idx: usize, page_id: String)
-        -> Self
+    /// Loads the `pages` of the document in `doc_dir`, those that fail are reported and skipped.
+    fn load_all<R: Read + Seek>(archive: &mut ZipArchive<R>, doc_dir: &str, pages: Vec<Page>) -> Vec<OFDPage> {
+        pages.into_iter()
+            .enumerate()
+            .filter_map(|(idx, page)| {
+                let path = resolve_loc(doc_dir, &page.base_loc);
+                OFDPage::load(archive, &path, idx, page.id)
+                    .map_err(|e| log::warn!("{}, page {} is skipped", e, idx))
+                    .ok()
+            })
+            .collect()
+    }
+
+    fn load<R: Read + Seek>(archive: &mut ZipArchive<R>, page_path: &str, idx: usize, page_id: String)
+        -> Result<Self, OFDError>
     {
         // println!("OFDPage new page_path: {:?}", page_path);
-        let mut z_f = archive.by_name(page_path).unwrap();
         let mut s = String::new();
-        let _ = &z_f.read_to_string(&mut s).unwrap();
+        archive.by_name(page_path).map_err(OFDError::ZipError)?
+            .read_to_string(&mut s)
+            .map_err(|e| OFDError::CustomError(format!("read {} failed: {}", page_path, e)))?;
 
-        let mut ofd_page: OFDPage = quick_xml::de::from_str(s.as_str()).expect("Failed to parse XML");
+        let mut ofd_page: OFDPage = quick_xml::de::from_str(s.as_str())
+            .map_err(|e| OFDError::CustomError(format!("parse {} failed: {}", page_path, e)))?;
         // println!("OFDPage new ofd_page: {:#?}", ofd_page);
         ofd_page.id = page_id;
         ofd_page.idx = idx;
-        ofd_page
+        ofd_page.dir = loc_dir(page_path).to_string();
+        ofd_page.resources = ofd_page.page_res.iter()
+            .filter_map(|loc| {
+                let path = resolve_loc(&ofd_page.dir, loc);
+                OFDRes::load(archive, path.as_str())
+                    .map_err(|e| log::warn!("{}, page {} is drawn without PageRes {}", e, ofd_page.id, loc))
+                    .ok()
+            })
+            .collect();
+        Ok(ofd_page)
     }
 
-    fn draw(&self, archive: &mut ZipArchive<File>, ofd_doc: &OFDDoc, base_name: &str) {
-        let mut p_box = self.area.map_or(
-            ofd_doc.physical_box.unwrap_or_default(),
-            |area| area.physical_box
-        );
+    /// Draws the page clipped to the `page_box` of `options`, `templates` caches the templates
+    /// of the document by ID.
+    fn draw(&self, archive: &mut ZipArchive<File>, ofd_doc: &OFDDoc, base_name: &str, options: RenderOptions,
+            templates: &mut HashMap<String, (ZOrder, ContentPage)>) {
+        let area = self.area.or(ofd_doc.page_area).unwrap_or_default();
+        let p_box = area.page_box(options.page_box);
         // println!("draw page p_box: {:?}", p_box);
 
-        p_box.width = p_box.width * PPMM;
-        p_box.height = p_box.height * PPMM;
-
-        let mut binding = backends::new_draw_backend(p_box.width as i32, p_box.height as i32);
+        let mut binding = backends::new_draw_backend(
+            (p_box.width * PPMM) as i32,
+            (p_box.height * PPMM) as i32,
+            options,
+        );
         let mut backend = binding.as_mut();
-
-        if self.template.is_some() {
-            let template_path: Option<String> = ofd_doc.template_pages.clone().map(
-                |pages| pages.into_iter().find(|tpl| tpl.id == self.template.as_ref().unwrap().id)
-                .unwrap().base_loc
-            );
-            // Step.1 draw template
-            // println!("template_path: {:?}", template_path);
-            if template_path.is_some() {
-                let mut z_f = archive.by_name(template_path.unwrap().as_str()).unwrap();
-                let mut s = String::new();
-                let _ = &z_f.read_to_string(&mut s).unwrap();
-                let content_page: ContentPage = quick_xml::de::from_str(s.as_str())
-                    .expect("Failed to parse XML");
-                // println!("content_page: {:#?}", content_page);
-                content_page.content.draw(backend);
+        // the output starts at the origin of the box
+        backend.draw_boundary(&PhysicalBox { x: -p_box.x, y: -p_box.y, ..p_box });
+
+        // page resources shadow the document's until the page is drawn
+        let _page_scope = ResourceScope::open();
+        register_resources(archive, &self.resources.iter().collect::<Vec<_>>(), ofd_doc.default_cs.as_deref());
+
+        // Step.1 draw the layers of the templates and the page, stacked
+        for template in &self.template {
+            let template_pages = ofd_doc.template_pages.as_deref().unwrap_or_default();
+            if let Err(e) = load_template(archive, template_pages, &template.id, templates) {
+                log::warn!("{}, page {} is drawn without template {}", e, self.id, template.id);
             }
         }
+        let templates: Vec<(ZOrder, &OFDContent)> = self.template.iter()
+            .filter_map(|template| {
+                let (z_order, content_page) = templates.get(&template.id)?;
+                Some((template.z_order.unwrap_or(*z_order), &content_page.content))
+            })
+            .collect();
+        for layer in stack_layers(&self.content, &templates) {
+            layer.draw(backend);
+        }
 
-        //     // Step.2 draw page
-        self.content.draw(backend);
-
-        // Step.3 draw annotations
-        ofd_doc.annotations.annotations.iter().for_each(|annot| {
+        // Step.2 draw annotations
+        let annotations = ofd_doc.annotations.iter()
+            .flat_map(|annotations| annotations.annotations.iter().map(move |annot| (annotations, annot)));
+        for (annotations, annot) in annotations {
             if annot.page_id == self.id {
-                let path = format!("{}/{}", ofd_doc.annotations.dir, annot.file_loc.text);
-                let mut z_f = archive.by_name(path.as_str()).unwrap();
-                let mut s = String::new();
-                let _ = &z_f.read_to_string(&mut s).unwrap();
-                let page_annot: PageAnnot = quick_xml::de::from_str(s.as_str()).expect("Failed to parse XML");
-                page_annot.draw(backend);
+                let path = resolve_loc(&annotations.dir, &annot.file_loc.text);
+                match read_xml::<_, PageAnnot>(archive, &path) {
+                    Ok(page_annot) => page_annot.draw(backend),
+                    Err(e) => log::warn!("{}, page {} is drawn without annotations {}", e, self.id, path),
+                }
             }
-        });
-
-        let seal_file: Option<ZipArchive<Cursor<Vec<u8>>>> = archive.by_name("Doc_0/Signs/Sign_0/SignedValue.dat")
-            .map_or(None, |mut file| {
-            let mut buf: Vec<u8> = Vec::new();
-            let _ = &file.read_to_end(&mut buf).unwrap();
-            let buff = Cursor::new(buf);
-            Some(ZipArchive::new(buff).unwrap())
-        });
-        let stamp = archive.by_name("Doc_0/Signs/Sign_0/Signature.xml")
-            .map_or(None, |mut file| {
-            let signature_ele = Element::parse(&mut file).unwrap();
-            let signed_info = signature_ele.get_child("SignedInfo").unwrap();
-            signed_info.get_child("StampAnnot")
-                .unwrap()
-                .attributes
-                .get("Boundary")
-                .map(|b| boundary_to_rect(b))
-        });
+        }
 
-        if seal_file.is_some() {
-            let mut seal_archive = seal_file.unwrap();
-            let mut z_f = seal_archive.by_name("Doc_0/PublicRes_0.xml").unwrap();
-            let mut s = String::new();
-            let _ = &z_f.read_to_string(&mut s).unwrap();
-            let seal_pub_res: OFDRes = quick_xml::de::from_str(&s).unwrap();
-            for font in &seal_pub_res.fonts {
+        // Step.3 draw the seals stamped on this page
+        for seal in &ofd_doc.seals {
+            if seal.page_ref.as_ref().is_some_and(|page_ref| *page_ref != self.id) {
+                continue;
+            }
+            // the seal's fonts shadow the page's while the seal is drawn
+            let _seal_scope = ResourceScope::open();
+            for font in seal.public_res.iter().flat_map(|res| &res.fonts) {
                 let family_name = font.family_name.clone().unwrap_or(font.font_name.clone());
                 RES_FONT_ID_MAP.lock().unwrap().insert(
                     font.id.clone(),
                     SendWrapper::new(get_font_from_family_name(family_name.as_str())),
                 );
             }
-            drop(z_f);
-
-            // for j in 0..seal_archive.len() {
-            //     let seal_f = seal_archive.by_index(j).unwrap();
-            //     println!("seal_f: {:?}", seal_f.name());
-            // }
-            // let seal_content = seal_archive.by_name("Doc_0/Pages/Page_0/Content.xml").unwrap();
-            let seal_page = OFDPage::new(
-                &mut seal_archive,
-                "Doc_0/Pages/Page_0/Content.xml",
-                0,
-                String::from("0"),
-            );
-            // println!("seal_page: {:?}", seal_page);
             let transform = backend.save();
-            backend.draw_boundary(stamp.as_ref().unwrap());
-            seal_page.content.draw(backend);
+            backend.draw_boundary(&seal.boundary);
+            seal.page.content.draw(backend);
             backend.restore(&transform);
         }
 
@@ -794,17 +1472,169 @@ impl OFDPage
 }
 
 impl OFDDoc {
-    pub fn draw_pages(&self, archive: &mut ZipArchive<File>, doc_name: &str) {
+    pub fn draw_pages(&self, archive: &mut ZipArchive<File>, doc_name: &str, options: RenderOptions) {
+        let mut templates = HashMap::new();
         for page in &self.pages {
-            page.draw(archive, self, doc_name);
+            page.draw(archive, self, doc_name, options, &mut templates);
+        }
+    }
+}
+
+/// A seal stamped on the document, its appearance is the first page of the seal's own OFD.
+#[derive(Debug, Clone)]
+pub struct Seal {
+    /// The page the seal is stamped on, every page if missing.
+    page_ref: Option<String>,
+    boundary: PhysicalBox,
+    public_res: Vec<OFDRes>,
+    page: OFDPage,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Signatures {
+    #[serde(rename = "Signature", default)]
+    signatures: Vec<SignatureRef>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SignatureRef {
+    #[serde(rename = "@ID")]
+    id: String,
+    #[serde(rename = "@BaseLoc")]
+    base_loc: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Signature {
+    signed_info: SignedInfo,
+    signed_value: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SignedInfo {
+    #[serde(default)]
+    stamp_annot: Vec<StampAnnot>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct StampAnnot {
+    #[serde(rename = "@PageRef")]
+    page_ref: Option<String>,
+    #[serde(rename = "@Boundary", deserialize_with = "deserialize_physical_box")]
+    boundary: PhysicalBox,
+}
+
+fn read_xml<R: Read + Seek, T: serde::de::DeserializeOwned>(archive: &mut ZipArchive<R>, path: &str)
+    -> Result<T, OFDError>
+{
+    let mut z_f = archive.by_name(path).map_err(OFDError::ZipError)?;
+    let mut s = String::new();
+    z_f.read_to_string(&mut s)
+        .map_err(|e| OFDError::CustomError(format!("read {} failed: {}", path, e)))?;
+    quick_xml::de::from_str(&s)
+        .map_err(|e| OFDError::CustomError(format!("parse {} failed: {}", path, e)))
+}
+
+/// Loads the seals of the signatures list at `path`, signatures that fail to load are skipped.
+fn load_seals<R: Read + Seek>(archive: &mut ZipArchive<R>, path: &str) -> Vec<Seal> {
+    let signatures: Signatures = match read_xml(archive, path) {
+        Ok(signatures) => signatures,
+        Err(e) => {
+            log::warn!("{}, the document is drawn without seals", e);
+            return Vec::new();
         }
+    };
+    let mut seals = Vec::new();
+    for signature in &signatures.signatures {
+        match load_signature_seals(archive, &resolve_loc(loc_dir(path), &signature.base_loc)) {
+            Ok(mut signature_seals) => seals.append(&mut signature_seals),
+            Err(e) => log::warn!("{}, signature {} is drawn without its seal", e, signature.id),
+        }
+    }
+    seals
+}
+
+fn load_signature_seals<R: Read + Seek>(archive: &mut ZipArchive<R>, path: &str)
+    -> Result<Vec<Seal>, OFDError>
+{
+    let signature: Signature = read_xml(archive, path)?;
+    let value_path = resolve_loc(loc_dir(path), &signature.signed_value);
+    let mut buf: Vec<u8> = Vec::new();
+    archive.by_name(&value_path).map_err(OFDError::ZipError)?
+        .read_to_end(&mut buf)
+        .map_err(|e| OFDError::CustomError(format!("read {} failed: {}", value_path, e)))?;
+    // only seals kept as an OFD package can be drawn
+    let mut seal_archive = ZipArchive::new(Cursor::new(buf))
+        .map_err(|e| OFDError::CustomError(format!("{} is not an OFD seal: {}", value_path, e)))?;
+
+    let ofd_element = seal_archive.by_name(OFD_XML)
+        .map_err(OFDError::ZipError)
+        .and_then(|file| Element::parse(file)
+            .map_err(|e| OFDError::CustomError(format!("parse seal {} failed: {}", OFD_XML, e))))?;
+    let doc_root = ofd_element.get_child("DocBody")
+        .and_then(|doc_body| doc_body.get_child("DocRoot"))
+        .and_then(|doc_root| doc_root.get_text())
+        .map(|loc| resolve_loc("", &loc))
+        .ok_or_else(|| OFDError::CustomError("seal DocRoot not found".to_string()))?;
+    let doc_dir = loc_dir(&doc_root).to_string();
+    let document: OFDDocument = read_xml(&mut seal_archive, &doc_root)?;
+    let public_res = OFDRes::load_all(&mut seal_archive, &doc_dir, &document.common_data.public_res);
+    let page = document.pages.page.into_iter().next()
+        .ok_or_else(|| OFDError::CustomError(format!("seal {} has no page", value_path)))?;
+    let page = OFDPage::load(&mut seal_archive, &resolve_loc(&doc_dir, &page.base_loc), 0, page.id)?;
+
+    Ok(signature.signed_info.stamp_annot.into_iter().map(|stamp| Seal {
+        page_ref: stamp.page_ref,
+        boundary: stamp.boundary,
+        public_res: public_res.clone(),
+        page: page.clone(),
+    }).collect())
+}
+
+/// Parses the template `id` of the document into `templates`, unless it's already there.
+fn load_template<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    template_pages: &[TemplatePage],
+    id: &str,
+    templates: &mut HashMap<String, (ZOrder, ContentPage)>,
+) -> Result<(), OFDError> {
+    if templates.contains_key(id) {
+        return Ok(());
     }
+    let template_page = template_pages.iter()
+        .find(|tpl| tpl.id == id)
+        .ok_or_else(|| OFDError::ResourceNotFound(format!("TemplatePage {}", id)))?;
+    let mut z_f = archive.by_name(template_page.base_loc.as_str()).map_err(OFDError::ZipError)?;
+    let mut s = String::new();
+    z_f.read_to_string(&mut s)
+        .map_err(|e| OFDError::CustomError(format!("read {} failed: {}", template_page.base_loc, e)))?;
+    let content_page: ContentPage = quick_xml::de::from_str(s.as_str())
+        .map_err(|e| OFDError::CustomError(format!("parse {} failed: {}", template_page.base_loc, e)))?;
+    templates.insert(id.to_string(), (template_page.z_order, content_page));
+    Ok(())
 }
 
 #[derive(Debug)]
 pub enum OFDError {
     ZipError(ZipError),
     CustomError(String),
+    ResourceNotFound(String),
+    DrawParamCycle(Vec<String>),
+}
+
+impl Display for OFDError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OFDError::ZipError(e) => write!(f, "zip error: {}", e),
+            OFDError::CustomError(s) => write!(f, "{}", s),
+            OFDError::ResourceNotFound(s) => write!(f, "resource not found: {}", s),
+            OFDError::DrawParamCycle(chain) => {
+                write!(f, "DrawParam Relative cycle: {}", chain.join(" -> "))
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -881,11 +1711,46 @@ impl OFDFile {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// The boxes of a page, in the page coordinates. A page's own `Area` replaces the
+/// document's `PageArea`.
+#[derive(Default, Debug, Clone, Copy, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct PageArea {
     #[serde(deserialize_with = "deserialize_physical_box")]
     pub physical_box: PhysicalBox,
+    /// The box shown to the reader, the crop box.
+    #[serde(default, deserialize_with = "deserialize_optional_physical_box")]
+    pub application_box: Option<PhysicalBox>,
+    #[serde(default, deserialize_with = "deserialize_optional_physical_box")]
+    pub content_box: Option<PhysicalBox>,
+    #[serde(default, deserialize_with = "deserialize_optional_physical_box")]
+    pub bleed_box: Option<PhysicalBox>,
+}
+
+/// The box of the page area a page is output clipped to.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum PageBox {
+    #[default]
+    Physical,
+    Application,
+    Content,
+    Bleed,
+}
+
+impl PageArea {
+    /// The box `page_box` of the area, the physical box if the area hasn't one.
+    pub fn page_box(&self, page_box: PageBox) -> PhysicalBox {
+        let b = match page_box {
+            PageBox::Physical => None,
+            PageBox::Application => self.application_box,
+            PageBox::Content => self.content_box,
+            PageBox::Bleed => self.bleed_box,
+        };
+        if b.is_none() && page_box != PageBox::Physical {
+            log::warn!("page area has no {:?} box, the physical box is output", page_box);
+        }
+        b.unwrap_or(self.physical_box)
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -895,8 +1760,12 @@ pub struct CommonData {
     pub max_unit_id: u32,
     pub template_page: Option<Vec<TemplatePage>>,
     pub page_area: Option<PageArea>,
-    pub public_res: String,
-    pub document_res: String,
+    #[serde(default)]
+    pub public_res: Vec<String>,
+    #[serde(default)]
+    pub document_res: Vec<String>,
+    #[serde(rename = "DefaultCS")]
+    pub default_cs: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -905,6 +1774,8 @@ pub struct TemplatePage {
     pub id: String,
     #[serde(rename = "@BaseLoc")]
     pub base_loc: String,
+    #[serde(rename = "@ZOrder", default)]
+    pub z_order: ZOrder,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -939,12 +1810,44 @@ where
     })
 }
 
+fn deserialize_optional_physical_box<'de, D>(deserializer: D) -> Result<Option<PhysicalBox>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_physical_box(deserializer).map(Some)
+}
+
+fn deserialize_pos<'de, D>(deserializer: D) -> Result<(f32, f32), D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    let v = s.split_whitespace()
+        .map(|v| v.parse::<f32>().map_err(serde::de::Error::custom))
+        .collect::<Result<Vec<f32>, D::Error>>()?;
+    match v[..] {
+        [x, y] => Ok((x, y)),
+        _ => Err(serde::de::Error::custom(format!("invalid position \"{}\"", s))),
+    }
+}
+
+fn deserialize_f32_array<'de, D>(deserializer: D) -> Result<Option<Vec<f32>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    s.split_whitespace()
+        .map(|v| v.parse::<f32>().map_err(serde::de::Error::custom))
+        .collect::<Result<Vec<f32>, D::Error>>()
+        .map(Some)
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct OFDDocument {
     pub common_data: CommonData,
     pub pages: Pages,
-    pub annotations: String,
+    pub annotations: Option<String>,
     pub attachments: Option<String>,
     pub custom_tags: Option<String>,
 }
@@ -952,8 +1855,12 @@ pub struct OFDDocument {
 
 #[cfg(test)]
 mod tests {
-    use crate::ofd::{ContentPage, OFDAnnotations, OFDDocument, OFDRes, PageAnnot};
-    use std::io::BufReader;
+    use crate::backends::{DrawBackend, Transform};
+    use crate::node_draw::{PathCommand, ResourceScope, MUTEX_RES_COMPOSITE_GRAPHIC_UNITS, MUTEX_RES_DRAW_PARAMS};
+    use crate::ofd::{resolve_draw_param, CommonData, ContentPage, DrawParam, DrawingComposite, ImageObject, LineCap, LineJoin, OFDAnnotations, OFDDocument, OFDError, OFDLayer, OFDPage, OFDRes, Page, PageAnnot, PageArea, PageBlock, PageBox, PhysicalBox, stack_layers, load_template, load_seals, register_resources, TemplatePage, ZOrder};
+    use crate::style::{Ctm, ResolvedClip, ResolvedPath, ResolvedText};
+    use std::collections::HashMap;
+    use std::io::{BufReader, Write};
 
     #[test]
     fn test_ofd() {
@@ -1004,6 +1911,481 @@ mod tests {
         println!("page_content: {:#?}", page_content);
     }
 
+    #[test]
+    fn test_des_draw_param_line_style() {
+        let xml = r#"<ofd:DrawParam xmlns:ofd="http://www.ofdspec.org/2016" ID="3" LineWidth="0.25"
+            Join="Round" Cap="Square" DashOffset="1" DashPattern="2 1.5" MiterLimit="5">
+            <ofd:StrokeColor Value="128 0 0"/>
+        </ofd:DrawParam>"#;
+        let draw_param: DrawParam = quick_xml::de::from_str(xml).expect("Failed to parse XML");
+        assert_eq!(draw_param.join, Some(LineJoin::Round));
+        assert_eq!(draw_param.cap, Some(LineCap::Square));
+        assert_eq!(draw_param.dash_offset, Some(1.));
+        assert_eq!(draw_param.dash_pattern, Some(vec![2., 1.5]));
+        assert_eq!(draw_param.miter_limit, Some(5.));
+
+        let xml = r#"<ofd:DrawParam xmlns:ofd="http://www.ofdspec.org/2016" ID="4"/>"#;
+        let draw_param: DrawParam = quick_xml::de::from_str(xml).expect("Failed to parse XML");
+        assert_eq!(draw_param.join, None);
+        assert_eq!(draw_param.dash_pattern, None);
+    }
+
+    fn draw_param(xml: &str) -> DrawParam {
+        quick_xml::de::from_str(xml).expect("Failed to parse XML")
+    }
+
+    #[test]
+    fn test_resolve_draw_param_chain() {
+        let draw_params: HashMap<String, DrawParam> = vec![
+            draw_param(r#"<DrawParam ID="1" LineWidth="0.25" Join="Round"><StrokeColor Value="1 1 1"/></DrawParam>"#),
+            draw_param(r#"<DrawParam ID="2" Relative="1" LineWidth="0.5"><FillColor Value="2 2 2"/></DrawParam>"#),
+            draw_param(r#"<DrawParam ID="3" Relative="2" Cap="Round"/>"#),
+        ].into_iter().map(|dp| (dp.id.clone(), dp)).collect();
+
+        let resolved = resolve_draw_param(draw_params.get("3").unwrap(), &draw_params).unwrap();
+        assert_eq!(resolved.line_width, Some(0.5));
+        assert_eq!(resolved.join, Some(LineJoin::Round));
+        assert_eq!(resolved.cap, Some(LineCap::Round));
+        assert_eq!(resolved.fill_color.unwrap().value.as_deref(), Some("2 2 2"));
+        assert_eq!(resolved.stroke_color.unwrap().value.as_deref(), Some("1 1 1"));
+    }
+
+    #[test]
+    fn test_resolve_draw_param_errors() {
+        let draw_params: HashMap<String, DrawParam> = vec![
+            draw_param(r#"<DrawParam ID="1" Relative="3"/>"#),
+            draw_param(r#"<DrawParam ID="2" Relative="1"/>"#),
+            draw_param(r#"<DrawParam ID="3" Relative="2"/>"#),
+            draw_param(r#"<DrawParam ID="4" Relative="404"/>"#),
+        ].into_iter().map(|dp| (dp.id.clone(), dp)).collect();
+
+        match resolve_draw_param(draw_params.get("1").unwrap(), &draw_params) {
+            Err(OFDError::DrawParamCycle(chain)) => assert_eq!(chain, vec!["1", "3", "2", "1"]),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(matches!(
+            resolve_draw_param(draw_params.get("4").unwrap(), &draw_params),
+            Err(OFDError::ResourceNotFound(_))
+        ));
+    }
+
+    /// Records the drawing calls, so tests can check what is drawn and in which order.
+    #[derive(Default)]
+    struct RecordingBackend {
+        calls: Vec<String>,
+    }
+
+    impl DrawBackend for RecordingBackend {
+        fn output_page(&mut self, _out_f_name: &String) -> crate::backends::Result<()> {
+            Ok(())
+        }
+        fn draw_boundary(&mut self, boundary: &PhysicalBox) {
+            self.calls.push(format!("boundary {} {}", boundary.x, boundary.y));
+        }
+        fn save(&mut self) -> Transform {
+            self.calls.push("save".to_string());
+            Transform::identity()
+        }
+        fn scale(&mut self) {}
+        fn concat(&mut self, transform: &Ctm) {
+            self.calls.push(format!("concat {:?}", transform.transform_point(1., 1.)));
+        }
+        fn restore(&mut self, _transform: &Transform) {
+            self.calls.push("restore".to_string());
+        }
+        fn draw_path(&mut self, path: &ResolvedPath) {
+            self.calls.push(format!("path {:?}", path.transform.transform_point(0., 0.)));
+        }
+        fn draw_text(&mut self, text: &ResolvedText) {
+            self.calls.push(format!("text {}", text.glyphs.iter().map(|g| g.c).collect::<String>()));
+        }
+        fn draw_image_object(&mut self, image_object: &ImageObject, _layer_draw_param_id: Option<&String>) {
+            self.calls.push(format!("image {}", image_object.resource_id));
+        }
+        fn begin_group(&mut self, alpha: u8) {
+            self.calls.push(format!("begin_group {}", alpha));
+        }
+        fn end_group(&mut self) {
+            self.calls.push("end_group".to_string());
+        }
+        fn push_clip(&mut self, clip: &ResolvedClip) {
+            self.calls.push(format!("push_clip {}", clip.shapes.len()));
+        }
+        fn pop_clip(&mut self) {
+            self.calls.push("pop_clip".to_string());
+        }
+    }
+
+    #[test]
+    fn test_draw_composite_object() {
+        let _scope = ResourceScope::open();
+        let res: OFDRes = quick_xml::de::from_str(
+            r#"<Res BaseLoc="Res">
+                <CompositeGraphicUnits>
+                    <CompositeGraphicUnit ID="test_vector_g_logo" Width="10" Height="10">
+                        <Thumbnail>5</Thumbnail>
+                        <Content><PathObject ID="1" Boundary="1 2 3 3"><AbbreviatedData>M 0 0 L 3 3</AbbreviatedData></PathObject></Content>
+                    </CompositeGraphicUnit>
+                    <CompositeGraphicUnit ID="test_vector_g_loop" Width="10" Height="10">
+                        <Content><CompositeObject ID="2" ResourceID="test_vector_g_loop" Boundary="0 0 10 10"/></Content>
+                    </CompositeGraphicUnit>
+                </CompositeGraphicUnits>
+            </Res>"#
+        ).unwrap();
+        assert_eq!(res.composite_graphic_units.len(), 2);
+        assert_eq!(res.composite_graphic_units[0].thumbnail.as_deref(), Some("5"));
+        for unit in &res.composite_graphic_units {
+            MUTEX_RES_COMPOSITE_GRAPHIC_UNITS.lock().unwrap().insert(unit.id.clone(), unit.clone());
+        }
+
+        let block: PageBlock = quick_xml::de::from_str(
+            r#"<PageBlock ID="3">
+                <CompositeObject ID="4" ResourceID="test_vector_g_logo" Boundary="100 200 20 20" CTM="2 0 0 2 0 0" Alpha="128"/>
+                <CompositeObject ID="5" ResourceID="test_vector_g_loop" Boundary="0 0 10 10"/>
+                <CompositeObject ID="6" ResourceID="test_vector_g_missing" Boundary="0 0 10 10"/>
+            </PageBlock>"#
+        ).unwrap();
+        let mut backend = RecordingBackend::default();
+        block.draw(&mut backend, None);
+        assert_eq!(backend.calls, vec![
+            "save", "concat (102.0, 202.0)", "begin_group 128", "path (1.0, 2.0)", "end_group", "restore",
+            // the unit containing itself is entered once
+            "save", "concat (1.0, 1.0)", "restore",
+        ]);
+
+        // a unit being drawn by one thread is drawn by another one alike
+        let _drawing = DrawingComposite::enter("test_vector_g_logo").unwrap();
+        assert!(DrawingComposite::enter("test_vector_g_logo").is_none());
+        let logo: PageBlock = quick_xml::de::from_str(
+            r#"<PageBlock ID="7"><CompositeObject ID="8" ResourceID="test_vector_g_logo" Boundary="0 0 10 10"/></PageBlock>"#
+        ).unwrap();
+        let logo_unit = res.composite_graphic_units[0].clone();
+        let calls = std::thread::spawn(move || {
+            let _scope = ResourceScope::open();
+            MUTEX_RES_COMPOSITE_GRAPHIC_UNITS.lock().unwrap().insert(logo_unit.id.clone(), logo_unit);
+            let mut backend = RecordingBackend::default();
+            logo.draw(&mut backend, None);
+            backend.calls
+        }).join().unwrap();
+        assert_eq!(calls, vec!["save", "concat (1.0, 1.0)", "path (1.0, 2.0)", "restore"]);
+    }
+
+    #[test]
+    fn test_draw_nested_page_blocks_in_order() {
+        let block: PageBlock = quick_xml::de::from_str(
+            r#"<ofd:PageBlock xmlns:ofd="http://www.ofdspec.org/2016" ID="1">
+                <ofd:TextObject ID="2" Boundary="0 0 10 10" Font="1" Size="3" Fill="false"><ofd:TextCode X="0" Y="3">a</ofd:TextCode></ofd:TextObject>
+                <ofd:PageBlock ID="3" Alpha="64">
+                    <ofd:ImageObject ID="4" ResourceID="5" Boundary="0 0 10 10"/>
+                    <ofd:PageBlock ID="6"><ofd:PathObject ID="7" Boundary="1 1 1 1"><ofd:AbbreviatedData>M 0 0</ofd:AbbreviatedData></ofd:PathObject></ofd:PageBlock>
+                </ofd:PageBlock>
+                <ofd:PathObject ID="8" Boundary="2 2 1 1"><ofd:AbbreviatedData>M 0 0</ofd:AbbreviatedData></ofd:PathObject>
+            </ofd:PageBlock>"#
+        ).unwrap();
+        let mut backend = RecordingBackend::default();
+        block.draw(&mut backend, None);
+        assert_eq!(backend.calls, vec![
+            "text a", "begin_group 64", "save", "concat (10.0, 10.0)", "image 5", "restore",
+            "path (1.0, 1.0)", "end_group", "path (2.0, 2.0)",
+        ]);
+    }
+
+    #[test]
+    fn test_draw_image_border() {
+        let image_object: ImageObject = quick_xml::de::from_str(
+            r#"<ofd:ImageObject xmlns:ofd="http://www.ofdspec.org/2016" ID="1" ResourceID="2" Boundary="5 5 20 10" CTM="20 0 0 10 0 0">
+                <ofd:Border LineWidth="0.5" HorizonalCornerRadius="2" VerticalCornerRadius="1">
+                    <ofd:BorderColor Value="255 0 0"/>
+                </ofd:Border>
+            </ofd:ImageObject>"#
+        ).unwrap();
+        let mut backend = RecordingBackend::default();
+        image_object.draw(&mut backend, None);
+        assert_eq!(backend.calls, vec![
+            "save", "concat (25.0, 15.0)", "image 2", "restore", "path (5.0, 5.0)",
+        ]);
+
+        let border = image_object.border.as_ref().unwrap();
+        let path = border.path_object(&image_object.boundary).resolve(None);
+        assert_eq!(path.line_style.width, 0.5);
+        assert!(path.fill.is_none() && path.stroke.is_some());
+        // four sides and four corners
+        assert_eq!(path.commands.iter().filter(|c| matches!(c, PathCommand::ArcTo { .. })).count(), 4);
+    }
+
+    #[test]
+    fn test_draw_layer_in_source_order() {
+        let layer: OFDLayer = quick_xml::de::from_str(
+            r#"<ofd:Layer xmlns:ofd="http://www.ofdspec.org/2016" ID="1">
+                <ofd:PathObject ID="2" Boundary="1 1 1 1"><ofd:AbbreviatedData>M 0 0</ofd:AbbreviatedData></ofd:PathObject>
+                <ofd:TextObject ID="3" Boundary="0 0 10 10" Font="1" Size="3" Fill="false"><ofd:TextCode X="0" Y="3">label</ofd:TextCode></ofd:TextObject>
+                <ofd:ImageObject ID="4" ResourceID="5" Boundary="0 0 10 10"/>
+                <ofd:PathObject ID="6" Boundary="2 2 1 1"><ofd:AbbreviatedData>M 0 0</ofd:AbbreviatedData></ofd:PathObject>
+            </ofd:Layer>"#
+        ).unwrap();
+        let mut backend = RecordingBackend::default();
+        layer.draw(&mut backend);
+        assert_eq!(backend.calls, vec![
+            "save", "path (1.0, 1.0)", "text label", "save", "concat (10.0, 10.0)", "image 5", "restore",
+            "path (2.0, 2.0)", "restore",
+        ]);
+    }
+
+    #[test]
+    fn test_draw_layer_skips_unknown_units() {
+        let layer: OFDLayer = quick_xml::de::from_str(
+            r#"<ofd:Layer xmlns:ofd="http://www.ofdspec.org/2016" ID="1">
+                <ofd:PathObject ID="2" Boundary="1 1 1 1"><ofd:AbbreviatedData>M 0 0</ofd:AbbreviatedData></ofd:PathObject>
+                <ofd:Annotation Type="Stamp" Version="1.0">
+                    <ofd:Property Name="Author">someone</ofd:Property>
+                    <ofd:Data><ofd:PathObject ID="3" Boundary="9 9 1 1"><ofd:AbbreviatedData>M 0 0</ofd:AbbreviatedData></ofd:PathObject></ofd:Data>
+                </ofd:Annotation>
+                <ofd:TextObject ID="4" Boundary="0 0 10 10" Font="1" Size="3" Fill="false"><ofd:TextCode X="0" Y="3">label</ofd:TextCode></ofd:TextObject>
+                <ofd:PathObject ID="5" Boundary="2 2 1 1"><ofd:AbbreviatedData>M 0 0</ofd:AbbreviatedData></ofd:PathObject>
+            </ofd:Layer>"#
+        ).unwrap();
+        let mut backend = RecordingBackend::default();
+        layer.draw(&mut backend);
+        // the path inside the unknown element isn't a unit of the layer
+        assert_eq!(backend.calls, vec!["save", "path (1.0, 1.0)", "text label", "path (2.0, 2.0)", "restore"]);
+    }
+
+    fn content(xml: &str) -> ContentPage {
+        quick_xml::de::from_str(xml).expect("Failed to parse XML")
+    }
+
+    #[test]
+    fn test_stack_layers() {
+        let page = content(r#"<Page><Content>
+            <Layer ID="p_fg" Type="Foreground"/><Layer ID="p_body"/><Layer ID="p_bg" Type="Background"/>
+        </Content></Page>"#);
+        let under = content(r#"<Page><Content><Layer ID="u_body"/><Layer ID="u_fg" Type="Foreground"/></Content></Page>"#);
+        let over = content(r#"<Page><Content><Layer ID="o_bg" Type="Background"/><Layer ID="o_body"/></Content></Page>"#);
+
+        let templates = vec![(ZOrder::Foreground, &over.content), (ZOrder::Background, &under.content)];
+        let ids: Vec<&str> = stack_layers(&page.content, &templates).iter()
+            .map(|layer| layer.id.as_str())
+            .collect();
+        assert_eq!(ids, vec!["p_bg", "o_bg", "u_body", "p_body", "o_body", "u_fg", "p_fg"]);
+    }
+
+    #[test]
+    fn test_load_template() {
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        zip.start_file("Doc_0/Tpls/Tpl_0/Content.xml", zip::write::FileOptions::default()).unwrap();
+        zip.write_all(br#"<Page><Content><Layer ID="1"/></Content></Page>"#).unwrap();
+        zip.start_file("Doc_0/Tpls/Tpl_1/Content.xml", zip::write::FileOptions::default()).unwrap();
+        zip.write_all(b"<Page><Content>").unwrap();
+        let mut archive = zip::ZipArchive::new(zip.finish().unwrap()).unwrap();
+
+        let template_pages: Vec<TemplatePage> = ["0", "1", "2"].iter().map(|id| TemplatePage {
+            id: id.to_string(),
+            base_loc: format!("Doc_0/Tpls/Tpl_{}/Content.xml", id),
+            z_order: ZOrder::Foreground,
+        }).collect();
+        let mut templates = HashMap::new();
+        load_template(&mut archive, &template_pages, "0", &mut templates).unwrap();
+        assert_eq!(templates.get("0").unwrap().0, ZOrder::Foreground);
+        assert_eq!(templates.get("0").unwrap().1.content.layers.len(), 1);
+        // cached templates aren't read again
+        load_template(&mut archive, &[], "0", &mut templates).unwrap();
+
+        assert!(matches!(
+            load_template(&mut archive, &template_pages, "1", &mut templates),
+            Err(OFDError::CustomError(_))
+        ));
+        assert!(matches!(
+            load_template(&mut archive, &template_pages, "2", &mut templates),
+            Err(OFDError::ZipError(_))
+        ));
+        assert!(matches!(
+            load_template(&mut archive, &template_pages, "3", &mut templates),
+            Err(OFDError::ResourceNotFound(_))
+        ));
+        assert_eq!(templates.len(), 1);
+    }
+
+    #[test]
+    fn test_page_area_boxes() {
+        let area: PageArea = quick_xml::de::from_str(r#"<Area>
+            <PhysicalBox>10 20 210 297</PhysicalBox>
+            <ApplicationBox>15 25 200 287</ApplicationBox>
+            <BleedBox>12 22 206 293</BleedBox>
+        </Area>"#).unwrap();
+        assert_eq!(area.page_box(PageBox::Physical).x, 10.0);
+        assert_eq!(area.page_box(PageBox::Application).width, 200.0);
+        assert_eq!(area.page_box(PageBox::Bleed).y, 22.0);
+        // no content box, falls back to the physical box
+        assert_eq!(area.page_box(PageBox::Content).height, 297.0);
+    }
+
+    #[test]
+    fn test_load_broken_annotations() {
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        zip.start_file("Doc_0/Annots/Annotations.xml", zip::write::FileOptions::default()).unwrap();
+        zip.write_all(br#"<Annotations><Page PageID="1">"#).unwrap();
+        let mut archive = zip::ZipArchive::new(zip.finish().unwrap()).unwrap();
+
+        assert!(OFDAnnotations::load(&mut archive, "Doc_0/Annots/Annotations.xml").is_err());
+        assert!(OFDAnnotations::load(&mut archive, "Doc_0/Annots/Missing.xml").is_err());
+    }
+
+    #[test]
+    fn test_load_missing_page() {
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        zip.start_file("Doc_0/Pages/Page_1/Content.xml", zip::write::FileOptions::default()).unwrap();
+        zip.write_all(br#"<Page><Content><Layer ID="3"/></Content></Page>"#).unwrap();
+        let mut archive = zip::ZipArchive::new(zip.finish().unwrap()).unwrap();
+
+        let pages = vec![
+            Page { id: "1".to_string(), base_loc: "Pages/Page_0/Content.xml".to_string() },
+            Page { id: "2".to_string(), base_loc: "Pages/Page_1/Content.xml".to_string() },
+        ];
+        let pages = OFDPage::load_all(&mut archive, "Doc_0", pages);
+        assert_eq!(pages.len(), 1);
+        assert_eq!((pages[0].id.as_str(), pages[0].idx), ("2", 1));
+    }
+
+    #[test]
+    fn test_load_seals() {
+        let options = zip::write::FileOptions::default();
+        let mut seal = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        seal.start_file("OFD.xml", options).unwrap();
+        seal.write_all(br#"<OFD><DocBody><DocRoot>/Doc_0/Document.xml</DocRoot></DocBody></OFD>"#).unwrap();
+        seal.start_file("Doc_0/Document.xml", options).unwrap();
+        seal.write_all(br#"<Document><CommonData><MaxUnitID>1</MaxUnitID>
+            <PublicRes>PublicRes.xml</PublicRes><DocumentRes>DocumentRes.xml</DocumentRes></CommonData>
+            <Pages><Page ID="1" BaseLoc="Pages/Page_0/Content.xml"/></Pages></Document>"#).unwrap();
+        seal.start_file("Doc_0/PublicRes.xml", options).unwrap();
+        seal.write_all(br#"<Res><Fonts><Font ID="2" FontName="SimSun"/></Fonts></Res>"#).unwrap();
+        seal.start_file("Doc_0/Pages/Page_0/Content.xml", options).unwrap();
+        seal.write_all(br#"<Page><Content><Layer ID="3"/></Content></Page>"#).unwrap();
+
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        zip.start_file("Doc_0/Signs/Signatures.xml", options).unwrap();
+        zip.write_all(br#"<Signatures>
+            <Signature ID="1" BaseLoc="Sign_0/Signature.xml"/>
+            <Signature ID="2" BaseLoc="/Doc_0/Signs/Sign_1/Signature.xml"/>
+            <Signature ID="3" BaseLoc="Sign_2/Signature.xml"/>
+        </Signatures>"#).unwrap();
+        zip.start_file("Doc_0/Signs/Sign_0/Signature.xml", options).unwrap();
+        zip.write_all(br#"<Signature><SignedInfo>
+            <StampAnnot ID="1" PageRef="5" Boundary="10 20 40 40"/>
+            <StampAnnot ID="2" Boundary="0 0 40 40"/>
+        </SignedInfo><SignedValue>../Sign_0/./SignedValue.dat</SignedValue></Signature>"#).unwrap();
+        zip.start_file("Doc_0/Signs/Sign_0/SignedValue.dat", options).unwrap();
+        zip.write_all(&seal.finish().unwrap().into_inner()).unwrap();
+        zip.start_file("Doc_0/Signs/Sign_1/Signature.xml", options).unwrap();
+        zip.write_all(br#"<Signature><SignedInfo><StampAnnot ID="1" Boundary="0 0 1 1"/></SignedInfo>
+            <SignedValue>SignedValue.dat</SignedValue></Signature>"#).unwrap();
+        zip.start_file("Doc_0/Signs/Sign_1/SignedValue.dat", options).unwrap();
+        zip.write_all(b"not a seal package").unwrap();
+        let mut archive = zip::ZipArchive::new(zip.finish().unwrap()).unwrap();
+
+        let seals = load_seals(&mut archive, "Doc_0/Signs/Signatures.xml");
+        assert_eq!(seals.len(), 2);
+        assert_eq!(seals[0].page_ref.as_deref(), Some("5"));
+        assert_eq!(seals[0].boundary.x, 10.0);
+        assert_eq!(seals[1].page_ref, None);
+        assert_eq!(seals[1].public_res[0].fonts[0].id, "2");
+        assert_eq!(seals[1].page.content.layers.len(), 1);
+        assert!(load_seals(&mut archive, "Doc_0/Signs/Missing.xml").is_empty());
+    }
+
+    #[test]
+    fn test_load_every_resource_file() {
+        let common_data: CommonData = quick_xml::de::from_str(r#"<CommonData><MaxUnitID>1</MaxUnitID>
+            <PublicRes>PublicRes.xml</PublicRes><PublicRes>PublicRes_1.xml</PublicRes>
+        </CommonData>"#).unwrap();
+        assert_eq!(common_data.public_res, ["PublicRes.xml", "PublicRes_1.xml"]);
+        assert!(common_data.document_res.is_empty());
+
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        zip.start_file("Doc_0/PublicRes_1.xml", zip::write::FileOptions::default()).unwrap();
+        zip.write_all(br#"<Res><Fonts><Font ID="1" FontName="SimSun"/></Fonts></Res>"#).unwrap();
+        let mut archive = zip::ZipArchive::new(zip.finish().unwrap()).unwrap();
+        // the missing file is skipped
+        let resources = OFDRes::load_all(&mut archive, "Doc_0", &common_data.public_res);
+        assert_eq!(resources.len(), 1);
+        assert_eq!(resources[0].fonts[0].id, "1");
+    }
+
+    #[test]
+    fn test_register_draw_params_across_files() {
+        let _scope = ResourceScope::open();
+        let mut archive = zip::ZipArchive::new(
+            zip::ZipWriter::new(std::io::Cursor::new(Vec::new())).finish().unwrap()
+        ).unwrap();
+        let public_res: OFDRes = quick_xml::de::from_str(r#"<Res><DrawParams>
+            <DrawParam ID="test_files_dp_public" Relative="test_files_dp_document" Cap="Round"/>
+        </DrawParams></Res>"#).unwrap();
+        let document_res: OFDRes = quick_xml::de::from_str(r#"<Res><DrawParams>
+            <DrawParam ID="test_files_dp_document" LineWidth="3"/>
+        </DrawParams></Res>"#).unwrap();
+        register_resources(&mut archive, &[&public_res, &document_res], None);
+
+        let draw_params = MUTEX_RES_DRAW_PARAMS.lock().unwrap();
+        let public = draw_params.get("test_files_dp_public").unwrap();
+        assert_eq!((public.line_width, public.cap), (Some(3.), Some(LineCap::Round)));
+    }
+
+    #[test]
+    fn test_register_page_resources() {
+        let _scope = ResourceScope::open();
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        zip.start_file("Doc_0/Pages/Page_0/Res/PageRes.xml", zip::write::FileOptions::default()).unwrap();
+        zip.write_all(br#"<Res>
+            <DrawParams>
+                <DrawParam ID="test_page_dp_shadowed" LineWidth="2"/>
+                <DrawParam ID="test_page_dp_child" Relative="test_page_dp_parent" Cap="Round"/>
+            </DrawParams>
+            <CompositeGraphicUnits>
+                <CompositeGraphicUnit ID="test_page_vector_g" Width="1" Height="1"><Content/></CompositeGraphicUnit>
+            </CompositeGraphicUnits>
+        </Res>"#).unwrap();
+        let mut archive = zip::ZipArchive::new(zip.finish().unwrap()).unwrap();
+
+        let document_res: OFDRes = quick_xml::de::from_str(r#"<Res><DrawParams>
+            <DrawParam ID="test_page_dp_shadowed" LineWidth="1"/>
+            <DrawParam ID="test_page_dp_parent" LineWidth="3"/>
+        </DrawParams></Res>"#).unwrap();
+        register_resources(&mut archive, &[&document_res], None);
+        let page_res = OFDRes::load(&mut archive, "Doc_0/Pages/Page_0/Res/PageRes.xml").unwrap();
+        assert!(OFDRes::load(&mut archive, "Doc_0/Pages/Page_0/Res/Missing.xml").is_err());
+        let line_width = |id: &str| MUTEX_RES_DRAW_PARAMS.lock().unwrap().get(id).and_then(|dp| dp.line_width);
+
+        {
+            let _page_scope = ResourceScope::open();
+            register_resources(&mut archive, &[&page_res], None);
+            assert_eq!(line_width("test_page_dp_shadowed"), Some(2.));
+            let draw_params = MUTEX_RES_DRAW_PARAMS.lock().unwrap();
+            let child = draw_params.get("test_page_dp_child").unwrap();
+            assert_eq!((child.line_width, child.cap), (Some(3.), Some(LineCap::Round)));
+            assert!(MUTEX_RES_COMPOSITE_GRAPHIC_UNITS.lock().unwrap().contains_key("test_page_vector_g"));
+        }
+
+        // another page sees the document's resources only
+        let _page_scope = ResourceScope::open();
+        assert_eq!(line_width("test_page_dp_shadowed"), Some(1.));
+        assert_eq!(line_width("test_page_dp_child"), None);
+        assert!(!MUTEX_RES_COMPOSITE_GRAPHIC_UNITS.lock().unwrap().contains_key("test_page_vector_g"));
+    }
+
+    #[test]
+    fn test_res_file_path() {
+        let mut res: OFDRes = quick_xml::de::from_str(r#"<Res BaseLoc="Res"/>"#).unwrap();
+        res.dir = "Doc_0".to_string();
+        assert_eq!(res.file_path("image_1.png"), "Doc_0/Res/image_1.png");
+        assert_eq!(res.file_path("/Doc_0/Other/image_1.png"), "Doc_0/Other/image_1.png");
+
+        res.base_loc = Some("/Doc_0/Shared/".to_string());
+        assert_eq!(res.file_path("image_1.png"), "Doc_0/Shared/image_1.png");
+        res.base_loc = None;
+        assert_eq!(res.file_path("image_1.png"), "Doc_0/image_1.png");
+        res.dir = String::new();
+        assert_eq!(res.file_path("image_1.png"), "image_1.png");
+    }
+
     #[test]
     fn test_path_join() {
         let path = std::path::Path::new("/a/b/c");
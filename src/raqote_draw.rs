@@ -1,10 +1,17 @@
+use std::sync::Arc;
+
 use raqote::*;
 use xmltree::Element;
-use crate::backends::DrawBackend;
+use crate::backends::{DrawBackend, RenderOptions};
 use crate::backends::DrawError::OutputError;
-use crate::node_draw::{_PathToken, abbreviate_data, boundary_to_rect, delta_to_vec, get_color_from_draw_param, MUTEX_IMAGE_RES, MUTEX_RES_DRAW_PARAMS, MUTEX_RGB_IMAGE_RES, ofd_color_from_v, OfdColor, PathToken, PPMM, RES_FONT_ID_MAP, Tag};
-use crate::ofd::{ImageObject, PathObject, PhysicalBox, TextObject};
+use crate::node_draw::{arc_to_cubics, boundary_to_rect, delta_to_vec, IMAGE_OBJECT, LineStyle, MUTEX_IMAGE_RES, MUTEX_RGB_IMAGE_RES, ofd_color_from_v, OfdColor, path_commands, PATH_OBJECT, PathCommand, PPMM, RES_FONT_ID_MAP, TEXT_OBJECT};
+use crate::ofd::{FillRule, ImageObject, PageBlock, PhysicalBox, ReflectMethod};
+use crate::shading::{GradientStop as ShadingStop, Shading};
+use crate::style::{ClipShape, Ctm, Fill, ResolvedClip, ResolvedPath, ResolvedText, TilingPattern};
+
 
+/// Largest width or height of a rendered pattern tile, in pixels.
+const MAX_TILE_SIZE: f32 = 2048.;
 
 macro_rules! unwrap_or_continue {
     ( $e:expr ) => {
@@ -16,6 +23,12 @@ macro_rules! unwrap_or_continue {
 }
 
 
+impl From<Ctm> for Transform {
+    fn from(ctm: Ctm) -> Self {
+        Transform::new(ctm.a, ctm.b, ctm.c, ctm.d, ctm.e, ctm.f)
+    }
+}
+
 impl OfdColor {
     fn solid_source(&self) -> Source {
         Source::Solid(SolidSource {
@@ -27,13 +40,169 @@ impl OfdColor {
     }
 }
 
+fn gradient(stops: &[ShadingStop]) -> Gradient {
+    Gradient {
+        stops: stops.iter().map(|stop| GradientStop {
+            position: stop.offset,
+            color: Color::new(stop.color.a, stop.color.r, stop.color.g, stop.color.b),
+        }).collect(),
+    }
+}
+
+/// Scales every channel of a premultiplied ARGB pixel by `alpha`.
+fn fade_premultiplied(pixel: u32, alpha: u8) -> u32 {
+    if alpha == 255 {
+        return pixel;
+    }
+    (0..4).map(|i| i * 8).fold(0, |faded, shift| {
+        let channel = (pixel >> shift & 0xff) * alpha as u32 / 255;
+        faded | channel << shift
+    })
+}
+
+/// The rendered cell of a pattern, in a tile of the steps' size mirrored as the pattern
+/// reflects. It's kept by the backend for every object filled with the same pattern.
+struct PatternTile {
+    cell: Arc<PageBlock>,
+    alpha: u8,
+    width: i32,
+    height: i32,
+    /// Maps the pattern space to the pixels of the tile.
+    scale: (f32, f32),
+    /// The premultiplied pixels.
+    pixels: Vec<u32>,
+}
+
+impl PatternTile {
+    fn render(pattern: &TilingPattern, options: RenderOptions) -> PatternTile {
+        let width = (pattern.x_step * PPMM).min(MAX_TILE_SIZE).ceil().max(1.) as i32;
+        let height = (pattern.y_step * PPMM).min(MAX_TILE_SIZE).ceil().max(1.) as i32;
+        let scale = (width as f32 / pattern.x_step, height as f32 / pattern.y_step);
+        let mut cell = RaqoteDrawBackend {
+            dt: DrawTarget::new(width, height),
+            clip_masks: Vec::new(),
+            pattern_tiles: Vec::new(),
+            options,
+        };
+        cell.dt.set_transform(&Transform::scale(scale.0, scale.1));
+        cell.dt.push_clip_rect(IntRect::new(
+            IntPoint::new(0, 0),
+            IntPoint::new((pattern.width * scale.0).ceil() as i32, (pattern.height * scale.1).ceil() as i32),
+        ));
+        pattern.cell.draw(&mut cell, None);
+        cell.dt.pop_clip();
+
+        let (columns, rows) = match pattern.reflect_method {
+            ReflectMethod::Normal => (1, 1),
+            ReflectMethod::Row => (2, 1),
+            ReflectMethod::Column => (1, 2),
+            ReflectMethod::RowAndColumn => (2, 2),
+        };
+        let data = cell.dt.get_data();
+        let mut pixels = Vec::with_capacity((width * columns * height * rows) as usize);
+        for y in 0..height * rows {
+            let sy = if y / height % 2 == 1 { height - 1 - y % height } else { y % height };
+            for x in 0..width * columns {
+                let sx = if x / width % 2 == 1 { width - 1 - x % width } else { x % width };
+                pixels.push(fade_premultiplied(data[(sy * width + sx) as usize], pattern.alpha));
+            }
+        }
+        PatternTile {
+            cell: pattern.cell.clone(),
+            alpha: pattern.alpha,
+            width: width * columns,
+            height: height * rows,
+            scale,
+            pixels,
+        }
+    }
+
+    /// Whether this is the tile of `pattern`: the cell is shared by the resolutions of
+    /// one `Pattern`, and held here so that it can't be mistaken for another one.
+    fn renders(&self, pattern: &TilingPattern) -> bool {
+        Arc::ptr_eq(&self.cell, &pattern.cell) && self.alpha == pattern.alpha
+    }
+}
+
+impl Fill {
+    /// The source painting the fill, `pixels` holds the premultiplied pixels of a mesh,
+    /// pattern tiles are rendered into `tiles` the first time they're used.
+    fn source<'a>(&'a self, pixels: &'a mut Vec<u32>, tiles: &'a mut Vec<PatternTile>, options: RenderOptions)
+        -> Source<'a>
+    {
+        match self {
+            Fill::Color(color) => color.solid_source(),
+            Fill::Shading(Shading::Axial { start, end, stops }) => Source::new_linear_gradient(
+                gradient(stops),
+                Point::new(start.0, start.1),
+                Point::new(end.0, end.1),
+                Spread::Pad,
+            ),
+            // the source transform maps user space to the space of the circles
+            Fill::Shading(Shading::Radial { start, start_radius, end, end_radius, transform, stops }) => {
+                Source::TwoCircleRadialGradient(
+                    gradient(stops),
+                    Spread::Pad,
+                    Point::new(start.0, start.1),
+                    *start_radius,
+                    Point::new(end.0, end.1),
+                    *end_radius,
+                    transform.invert().map_or(Transform::identity(), Transform::from),
+                )
+            }
+            Fill::Shading(Shading::Mesh { image, transform }) => {
+                *pixels = image.pixels().map(|p| {
+                    let [r, g, b, a] = p.0;
+                    let premultiply = |c: u8| (c as u32 * a as u32 + 127) / 255;
+                    (a as u32) << 24 | premultiply(r) << 16 | premultiply(g) << 8 | premultiply(b)
+                }).collect();
+                Source::Image(
+                    Image {
+                        width: image.width() as i32,
+                        height: image.height() as i32,
+                        data: pixels.as_slice(),
+                    },
+                    ExtendMode::Pad,
+                    FilterMode::Bilinear,
+                    transform.invert().map_or(Transform::identity(), Transform::from),
+                )
+            }
+            Fill::Pattern(pattern) => {
+                let tile = match tiles.iter().position(|tile| tile.renders(pattern)) {
+                    Some(idx) => &tiles[idx],
+                    None => {
+                        tiles.push(PatternTile::render(pattern, options));
+                        &tiles[tiles.len() - 1]
+                    }
+                };
+                let transform = Ctm::scale(1. / tile.scale.0, 1. / tile.scale.1).then(&pattern.transform);
+                Source::Image(
+                    Image {
+                        width: tile.width,
+                        height: tile.height,
+                        data: tile.pixels.as_slice(),
+                    },
+                    ExtendMode::Repeat,
+                    FilterMode::Bilinear,
+                    transform.invert().map_or(Transform::identity(), Transform::from),
+                )
+            }
+        }
+    }
+}
+
 
 pub struct RaqoteDrawBackend {
     pub dt: DrawTarget,
+    /// The masks of the pushed clips with several shapes, `None` for those clipping the
+    /// draw target itself.
+    clip_masks: Vec<Option<DrawTarget>>,
+    pattern_tiles: Vec<PatternTile>,
+    options: RenderOptions,
 }
 
 impl RaqoteDrawBackend {
-    pub fn new(width: i32, height: i32) -> RaqoteDrawBackend {
+    pub fn new(width: i32, height: i32, options: RenderOptions) -> RaqoteDrawBackend {
         let mut dt = DrawTarget::new(width, height);
         dt.fill_rect(
             0.,
@@ -50,7 +219,10 @@ impl RaqoteDrawBackend {
         );
         dt.set_transform(&Transform::scale(PPMM, PPMM));
         RaqoteDrawBackend {
-            dt
+            dt,
+            clip_masks: Vec::new(),
+            pattern_tiles: Vec::new(),
+            options,
         }
     }
 }
@@ -80,6 +252,11 @@ impl DrawBackend for RaqoteDrawBackend {
 
     fn scale(&mut self) {}
 
+    fn concat(&mut self, transform: &Ctm) {
+        let t = Transform::from(*transform).then(self.dt.get_transform());
+        self.dt.set_transform(&t);
+    }
+
     fn restore(&mut self, transform: &crate::backends::Transform) {
         self.dt.set_transform(&Transform::new(
             transform.m11,
@@ -91,16 +268,69 @@ impl DrawBackend for RaqoteDrawBackend {
         ));
     }
 
-    fn draw_path_object(&mut self, draw_param_id: Option<&String>, path_object: &PathObject) {
-        draw_path_object(&mut self.dt, draw_param_id, path_object);
+    fn draw_path(&mut self, path: &ResolvedPath) {
+        draw_path_object(&mut self.dt, path, &mut self.pattern_tiles, self.options);
+    }
+
+    fn draw_text(&mut self, text: &ResolvedText) {
+        draw_text_object(&mut self.dt, text, &mut self.pattern_tiles, self.options);
+    }
+
+    fn draw_image_object(&mut self, image_object: &ImageObject, layer_draw_param_id: Option<&String>) {
+        draw_image_object(&mut self.dt, image_object, layer_draw_param_id, self.options);
+    }
+
+    fn begin_group(&mut self, alpha: u8) {
+        self.dt.push_layer(alpha as f32 / 255.);
     }
 
-    fn draw_text_object(&mut self, draw_param_id: Option<&String>, text_object: &TextObject) {
-        draw_text_object(&mut self.dt, draw_param_id, text_object);
+    fn end_group(&mut self) {
+        self.dt.pop_layer();
+    }
+
+    fn push_clip(&mut self, clip: &ResolvedClip) {
+        let path = |shape: &ClipShape| build_path(&shape.commands, shape.fill_rule)
+            .transform(&Transform::from(shape.transform));
+        if let [shape] = clip.shapes.as_slice() {
+            self.dt.push_clip(&path(shape));
+            self.clip_masks.push(None);
+            return;
+        }
+        // without path operations the union of the shapes is their coverage, filled one
+        // by one in a mask which cuts the content drawn in a layer out when popped
+        let mut mask = DrawTarget::new(self.dt.width(), self.dt.height());
+        mask.set_transform(self.dt.get_transform());
+        let opaque = Source::Solid(SolidSource { r: 0xff, g: 0xff, b: 0xff, a: 0xff });
+        for shape in &clip.shapes {
+            mask.fill(&path(shape), &opaque, &DrawOptions::new());
+        }
+        self.dt.push_layer(1.);
+        self.clip_masks.push(Some(mask));
     }
 
-    fn draw_image_object(&mut self, image_object: &ImageObject) {
-        draw_image_object(&mut self.dt, image_object);
+    fn pop_clip(&mut self) {
+        match self.clip_masks.pop() {
+            Some(Some(mask)) => {
+                let trans = self.dt.get_transform().clone();
+                self.dt.set_transform(&Transform::identity());
+                self.dt.draw_image_at(
+                    0.,
+                    0.,
+                    &Image {
+                        width: mask.width(),
+                        height: mask.height(),
+                        data: mask.get_data(),
+                    },
+                    &DrawOptions {
+                        blend_mode: BlendMode::DstIn,
+                        ..DrawOptions::new()
+                    },
+                );
+                self.dt.set_transform(&trans);
+                self.dt.pop_layer();
+            }
+            _ => self.dt.pop_clip(),
+        }
     }
 }
 
@@ -120,71 +350,30 @@ pub fn draw_ele_recursive(backend: &mut RaqoteDrawBackend, element: &Element) {
     }
 }
 
-pub fn draw_path_object(dt: &mut DrawTarget, draw_param_id: Option<&String>, path_object: &PathObject) {
-    let draw_param = draw_param_id.map_or(
-        None,
-        |it| {
-            Some(MUTEX_RES_DRAW_PARAMS.lock().unwrap().get(it).unwrap().clone())
-        }
-    );
-    let line_width: f32 = path_object.line_width.unwrap_or(0.5);
-    let boundary = &path_object.boundary;
+fn draw_path_object(dt: &mut DrawTarget, path: &ResolvedPath, tiles: &mut Vec<PatternTile>, options: RenderOptions) {
+    let new_path = build_path(&path.commands, path.fill_rule);
 
-    let tokens = abbreviate_data(&path_object.abbreviated_data);
-    let ctm = path_object.ctm.clone().map_or(
-        Transform::identity(),
-        |s| attr_to_transform(&s)
-    );
-
-    let fill_color = path_object.fill_color.clone().map_or(
-        OfdColor::default(),
-        |s| match s.value {
-            None => draw_param.as_ref().map_or(
-                OfdColor::default(),
-                |dp| dp.fill_color.clone().map_or(
-                    OfdColor::default(),
-                    |c| ofd_color_from_v(&c.value.unwrap_or("128 0 0".to_string()))
-                )
-            ),
-            Some(c) => ofd_color_from_v(&c),
-        }
-    );
-    let stroke_color = path_object.stroke_color.clone().map_or(
-        draw_param.as_ref().map_or(
-            OfdColor::default(),
-            |dp| dp.stroke_color.clone().map_or(
-                OfdColor::default(),
-                |c| ofd_color_from_v(&c.value.unwrap_or("128 0 0".to_string()))
-            )
-        ),
-        |s| match s.value {
-            None => draw_param.as_ref().map_or(
-                OfdColor::default(),
-                |dp| dp.stroke_color.clone().map_or(
-                    OfdColor::default(),
-                    |c| ofd_color_from_v(&c.value.unwrap_or("128 0 0".to_string()))
-                )
-            ),
-            Some(c) => ofd_color_from_v(&c),
-        }
-    );
+    if path.alpha < 255 {
+        dt.push_layer(path.alpha as f32 / 255.);
+    }
     let trans = dt.get_transform().clone();
-    let t = ctm
-        .then_translate(Vector::new(boundary.x, boundary.y))
-        .then(&trans)
-        .clone();
-    // println!("   {:?}, {:?}", trans, t);
-    dt.set_transform(&t);
-    // println!("draw_path_object line_width: {}", line_width);
-    draw_abbreviate_path(
-        dt,
-        &Point::new(0., 0.),
-        tokens,
-        &line_width,
-        &fill_color,
-        &stroke_color,
-    );
+    dt.set_transform(&Transform::from(path.transform).then(&trans));
+    let mut pixels = Vec::new();
+    if let Some(fill) = &path.fill {
+        dt.fill(&new_path, &fill.source(&mut pixels, tiles, options), &DrawOptions::new());
+    }
+    if let Some(stroke) = &path.stroke {
+        dt.stroke(
+            &new_path,
+            &stroke.source(&mut pixels, tiles, options),
+            &stroke_style(&path.line_style),
+            &DrawOptions::new(),
+        );
+    }
     dt.set_transform(&trans);
+    if path.alpha < 255 {
+        dt.pop_layer();
+    }
 }
 
 pub fn draw_path(dt: &mut DrawTarget, element: &Element) {
@@ -222,7 +411,7 @@ pub fn draw_path(dt: &mut DrawTarget, element: &Element) {
         .unwrap()
         .clone();
     // println!("abbr_data: {}", abbr_data);
-    let tokens = abbreviate_data(&String::from(abbr_data.as_ref()));
+    let commands = path_commands(&String::from(abbr_data.as_ref()));
     let ctm = element
         .attributes
         .get("CTM")
@@ -238,97 +427,49 @@ pub fn draw_path(dt: &mut DrawTarget, element: &Element) {
     draw_abbreviate_path(
         dt,
         &Point::new(0., 0.),
-        tokens,
-        &line_width,
+        commands,
+        &LineStyle {
+            width: line_width,
+            ..Default::default()
+        },
         &fill_color,
         &stroke_color,
     );
     dt.set_transform(&trans);
 }
 
-pub fn draw_text_object(dt: &mut DrawTarget, draw_param_id: Option<&String>, text_object: &TextObject) {
-    let (dp_fill_color, dp_stroke_color) = get_color_from_draw_param(draw_param_id);
-    let boundary = text_object.boundary;
-    let font_id = text_object.font.clone();
-    let size: f32 = text_object.size;
-    let fill_color = text_object.fill_color.clone().map_or(
-        OfdColor::default(),
-        |s| match s.value {
-            None => dp_fill_color.unwrap_or(OfdColor::default()),
-            Some(c) => ofd_color_from_v(&c)
+fn draw_text_object(dt: &mut DrawTarget, text: &ResolvedText, tiles: &mut Vec<PatternTile>, options: RenderOptions) {
+    let fill = match &text.fill {
+        Some(fill) => fill,
+        None => return,
+    };
+    let font = match RES_FONT_ID_MAP.lock().unwrap().get(text.font.as_str()) {
+        Some(font) => font.clone().take(),
+        None => {
+            log::warn!("font {} not found, text not drawn", text.font);
+            return;
         }
-    );
-    let font = RES_FONT_ID_MAP.lock().unwrap().get(font_id.as_str()).unwrap().clone().take();
-
-    let ctm = text_object.ctm.clone().map_or(
-        Transform::identity(),
-        |s| attr_to_transform(&s));
-
-    let text_code = text_object.text_code.clone();
-    let mut iter_delta_x = text_code.delta_x.map_or(
-        vec![0.; text_code.text.len()].into_iter(),
-        |s| delta_to_vec(&s).into_iter()
-    );
-
-    let mut iter_delta_y = text_code.delta_y.map_or(
-        vec![0.; text_code.text.len()].into_iter(),
-        |s| delta_to_vec(&s).into_iter()
-    );
-
-    // println!("iter_delta_x: {:?}, iter_delta_y: {:?}", iter_delta_x, iter_delta_y);
-    let mut start_p = Point::new(text_code.x, text_code.y);
-    start_p.x += boundary.x;
-    start_p.y += boundary.y;
-    let mut positions = Vec::new();
-    let point_size = size; // * PPMM
-
-    let m = dt.get_transform().clone();
-    // println!("draw_text_object {:?} matrix: {:?}", text_object.text_code, m);
-    if !ctm.eq(&Transform::identity()) {
-        // WARNING: It's very wired when the ctm is not identity the x is -x in windows.
-        start_p.x = -start_p.x + 2. * boundary.x;
-
-        // println!("draw_text ctm: {:?}, text: {}", ctm, text);
-        // the ctm is implied on the original point [boundary.left_top]
-        let mm = Transform::identity()
-            .pre_translate(Vector::new(-start_p.x, -start_p.y))
-            .then(&ctm)
-            .then_translate(Vector::new(start_p.x, start_p.y))
-            .then(&m);
-        dt.set_transform(&mm);
-    } else {
-        // println!("draw_text ctm: {:?}, text: {}", ctm, text);
-        // the ctm is implied on the original point [boundary.left_top]
-        let mm = Transform::identity()
-            .pre_translate(Vector::new(-boundary.x, -boundary.y))
-            .then(&ctm)
-            .then_translate(Vector::new(boundary.x, boundary.y))
-            .then(&m);
-        dt.set_transform(&mm);
-    }
+    };
 
     let mut ids = Vec::new();
-    for c in text_code.text.chars() {
-        let id = font.glyph_for_char(c).unwrap();
-        ids.push(id);
-        positions.push(Point::new(start_p.x, start_p.y));
-        // let offset_p = m.transform_point(Point::new(iter_delta_x.next().unwrap_or(0.),  iter_delta_y.next().unwrap_or(0.)));
-        let offset_p = Point::new(
-            iter_delta_x.next().unwrap_or(0.),
-            iter_delta_y.next().unwrap_or(0.),
-        );
-        start_p.x += offset_p.x;
-        start_p.y += offset_p.y;
+    let mut positions = Vec::new();
+    for glyph in &text.glyphs {
+        ids.push(font.glyph_for_char(glyph.c).unwrap_or(0));
+        positions.push(Point::new(glyph.x, glyph.y));
     }
 
-    let options = &DrawOptions::new();
+    let m = dt.get_transform().clone();
+    dt.set_transform(&Transform::from(text.transform).then(&m));
     dt.draw_glyphs(
         &font,
-        point_size,
+        text.size,
         &ids,
         &positions,
-        &fill_color.solid_source(),
-        options,
+        &fill.source(&mut Vec::new(), tiles, options),
+        &DrawOptions {
+            alpha: text.alpha as f32 / 255.,
+            ..DrawOptions::new()
+        },
     );
     dt.set_transform(&m);
 }
@@ -466,37 +607,31 @@ pub fn draw_text(dt: &mut DrawTarget, element: &Element) {
     dt.set_transform(&m);
 }
 
-pub fn draw_image_object(dt: &mut DrawTarget, image_object: &ImageObject) {
+pub fn draw_image_object(dt: &mut DrawTarget, image_object: &ImageObject, layer_draw_param_id: Option<&String>,
+                         options: RenderOptions) {
     // println!("draw_image_object: {:#?}", image_object);
-    let _id  = image_object.id.clone();
-    let img_file = MUTEX_IMAGE_RES.lock().unwrap().get(image_object.resource_id.as_str()).unwrap().clone();
-    let img = MUTEX_RGB_IMAGE_RES.lock().unwrap().get(&img_file).unwrap().clone();
-
-    let d1: Vec<u32> = img.pixels().into_iter()
-        .map(|p| {
-            // ((p[3] as u32) << 24) | ((p[2] as u32) << 16) | ((p[1] as u32) << 8) | (p[0] as u32)
-            if p == &image::Rgba([255, 255, 255, 0]) {
-                return 0;
-            }
-            ((p[3] as u32) << 24) | ((p[0] as u32) << 16) | ((p[1] as u32) << 8) | (p[2] as u32)
-        })
-        .collect();
+    let img = match image_object.resolve(layer_draw_param_id, options.use_image_substitutions) {
+        Some(img) => img,
+        None => return,
+    };
+    let d1 = premultiplied_argb(&img);
 
     let image = Image {
         width: img.width() as i32,
         height: img.height() as i32,
         data: &d1,
     };
-    let physical_box = image_object.boundary.clone();
-
-    // println!("draw_image_object: {:?}", physical_box);
+    // the unit square, placed by the transform of the image object
     dt.draw_image_with_size_at(
-        physical_box.width,
-        physical_box.height,
-        physical_box.x,
-        physical_box.y,
+        1.,
+        1.,
+        0.,
+        0.,
         &image,
-        &DrawOptions::new(),
+        &DrawOptions {
+            alpha: image_object.alpha as f32 / 255.,
+            ..DrawOptions::new()
+        },
     );
 }
 
@@ -520,13 +655,7 @@ fn draw_image(dt: &mut DrawTarget, element: &Element) {
         .clone();
 
     let physical_box = boundary_to_rect(boundary.unwrap());
-    let d1: Vec<u32> = img
-        .pixels()
-        .into_iter()
-        .map(|p| {
-            ((p[3] as u32) << 24) | ((p[0] as u32) << 16) | ((p[1] as u32) << 8) | (p[2] as u32)
-        })
-        .collect();
+    let d1 = premultiplied_argb(&img);
 
     let image = Image {
         width: img.width() as i32,
@@ -545,112 +674,99 @@ fn draw_image(dt: &mut DrawTarget, element: &Element) {
     );
 }
 
+/// The pixels of `img` as raqote wants them, premultiplied ARGB.
+fn premultiplied_argb(img: &image::RgbaImage) -> Vec<u32> {
+    img.pixels()
+        .map(|p| {
+            let a = p[3] as u32;
+            let premultiply = |c: u8| (c as u32 * a + 127) / 255;
+            (a << 24) | (premultiply(p[0]) << 16) | (premultiply(p[1]) << 8) | premultiply(p[2])
+        })
+        .collect()
+}
+
+fn build_path(commands: &[PathCommand], fill_rule: FillRule) -> Path {
+    let mut pb = PathBuilder::new();
+    // current point and start of the current subpath, needed by arcs and closing
+    let mut current = (0., 0.);
+    let mut subpath_start = (0., 0.);
+    for command in commands {
+        match *command {
+            PathCommand::MoveTo(x, y) => {
+                pb.move_to(x, y);
+                current = (x, y);
+                subpath_start = current;
+            }
+            PathCommand::LineTo(x, y) => {
+                pb.line_to(x, y);
+                current = (x, y);
+            }
+            PathCommand::QuadTo(x1, y1, x, y) => {
+                pb.quad_to(x1, y1, x, y);
+                current = (x, y);
+            }
+            PathCommand::CubicTo(x1, y1, x2, y2, x, y) => {
+                pb.cubic_to(x1, y1, x2, y2, x, y);
+                current = (x, y);
+            }
+            PathCommand::ArcTo { rx, ry, x_axis_rotation, large_arc, sweep, x, y } => {
+                for c in arc_to_cubics(current, (rx, ry), x_axis_rotation, large_arc, sweep, (x, y)) {
+                    pb.cubic_to(c[0], c[1], c[2], c[3], c[4], c[5]);
+                }
+                current = (x, y);
+            }
+            PathCommand::Close => {
+                pb.close();
+                current = subpath_start;
+            }
+        }
+    }
+    let mut path = pb.finish();
+    path.winding = match fill_rule {
+        FillRule::NonZero => Winding::NonZero,
+        FillRule::EvenOdd => Winding::EvenOdd,
+    };
+    path
+}
+
 fn draw_abbreviate_path(
     dt: &mut DrawTarget,
     start_p: &Point,
-    path: Vec<PathToken>,
-    line_width: &f32,
+    commands: Vec<PathCommand>,
+    line_style: &LineStyle,
     #[allow(unused_variables)] fill_color: &OfdColor,
     stroke_color: &OfdColor,
 ) {
     // println!("draw_abbreviate_path:, {:?}", stroke_color);
-    let mut idx = 0;
-    let mut pb = PathBuilder::new();
-    while idx < path.len() {
-        unsafe {
-            match path.get(idx) {
-                Some(PathToken {
-                         tag: Tag::C,
-                         token: _PathToken { op: 'M' },
-                     }) => {
-                    idx += 1;
-                    let x = path.get(idx).unwrap().token.v;
-                    idx += 1;
-                    let y = path.get(idx).unwrap().token.v;
-                    pb.move_to(x, y);
-                }
-                Some(PathToken {
-                         tag: Tag::C,
-                         token: _PathToken { op: 'L' },
-                     }) => {
-                    let mut iter = path[idx + 1..idx + 3].iter().map(|pt| pt.token.v);
-                    idx += 2;
-                    let x = iter.next().unwrap();
-                    let y = iter.next().unwrap();
-                    pb.line_to(x, y);
-                }
-                Some(PathToken {
-                         tag: Tag::C,
-                         token: _PathToken { op: 'B' },
-                     }) => {
-                    let mut iter = path[idx + 1..idx + 7].iter().map(|pt| pt.token.v);
-                    idx += 6;
-                    pb.cubic_to(
-                        iter.next().unwrap(),
-                        iter.next().unwrap(),
-                        iter.next().unwrap(),
-                        iter.next().unwrap(),
-                        iter.next().unwrap(),
-                        iter.next().unwrap(),
-                    );
-                }
-                Some(PathToken {
-                         tag: Tag::C,
-                         token: _PathToken { op: 'A' },
-                     }) => {
-                    // pb.arc()
-                }
-                Some(PathToken {
-                         tag: Tag::C,
-                         token: _PathToken { op: 'Q' },
-                     }) => {
-                    let mut iter = path[idx + 1..idx + 5].iter().map(|pt| pt.token.v);
-                    idx += 4;
-
-                    pb.quad_to(
-                        iter.next().unwrap(),
-                        iter.next().unwrap(),
-                        iter.next().unwrap(),
-                        iter.next().unwrap(),
-                    );
-                }
-                Some(PathToken {
-                         tag: Tag::C,
-                         token: _PathToken { op: 'C' },
-                     }) => {}
-                Some(
-                    pt @ PathToken {
-                        tag: Tag::C,
-                        token: _,
-                    },
-                ) => {
-                    panic!("OFD path_token [{}] invalid!", pt);
-                }
-                Some(_) => {}
-                None => {}
-            }
-        }
-        idx += 1;
-    }
-    pb.close();
-    let path = pb.finish();
+    let path = build_path(&commands, FillRule::NonZero);
     let new_path = path.transform(&Transform::translation(start_p.x, start_p.y));
     dt.stroke(
         &new_path,
-        &Source::Solid(SolidSource {
-            r: stroke_color.r,
-            g: stroke_color.g,
-            b: stroke_color.b,
-            a: stroke_color.a,
-        }),
-        &StrokeStyle {
-            width: line_width.clone(),
-            ..Default::default()
-        },
+        &stroke_color.solid_source(),
+        &stroke_style(line_style),
         &DrawOptions::new(),
     );
 }
 
+fn stroke_style(line_style: &LineStyle) -> StrokeStyle {
+    StrokeStyle {
+        width: line_style.width,
+        join: match line_style.join {
+            crate::ofd::LineJoin::Miter => LineJoin::Miter,
+            crate::ofd::LineJoin::Round => LineJoin::Round,
+            crate::ofd::LineJoin::Bevel => LineJoin::Bevel,
+        },
+        cap: match line_style.cap {
+            crate::ofd::LineCap::Butt => LineCap::Butt,
+            crate::ofd::LineCap::Round => LineCap::Round,
+            crate::ofd::LineCap::Square => LineCap::Square,
+        },
+        miter_limit: line_style.miter_limit,
+        dash_array: line_style.dash_intervals(),
+        dash_offset: line_style.dash_offset,
+    }
+}
+
 fn attr_to_transform(ctm: &String) -> Transform {
     let vec: Vec<f32> = ctm.split_whitespace().map(|s| s.parse().unwrap()).collect();
 
@@ -664,9 +780,12 @@ fn attr_to_transform(ctm: &String) -> Transform {
 #[cfg(test)]
 mod tests {
     use std::cmp::min;
-    use super::{abbreviate_data, draw_abbreviate_path};
-    use super::{delta_to_vec, OfdColor};
-    use crate::ofd::PhysicalBox;
+    use super::{path_commands, draw_abbreviate_path, RaqoteDrawBackend};
+    use super::{delta_to_vec, LineStyle, OfdColor};
+    use crate::backends::{DrawBackend, RenderOptions};
+    use crate::node_draw::PathCommand;
+    use crate::ofd::{FillRule, PhysicalBox};
+    use crate::style::{ClipShape, Ctm, ResolvedClip};
     use crate::node_draw::{get_font_from_family_name, PPMM};
     use euclid::Angle;
     use font_kit::family_name::FamilyName;
@@ -819,7 +938,7 @@ mod tests {
         let data = "M 10.07 5.54 B 10.07 3.04 8.04 1 5.53 1 B 3.03 1 1 3.04 1 5.54 B 1 8.04 3.03 10.08 5.53 10.08 B 8.04 10.08 10.07 8.04 10.07 5.54 M 2.3 2.3 L 8.7 8.7 M 2.3 8.7 L 8.7 2.3";
         // let data = "M 0 0 L 100 0 L 100 100 L 0 100 L 0 0";
         println!("data: {}", data);
-        let tokens = abbreviate_data(&data.into());
+        let commands = path_commands(&data.into());
         let mut dt = DrawTarget::new(400, 400);
 
         dt.fill_rect(
@@ -836,7 +955,7 @@ mod tests {
             &DrawOptions::new(),
         );
 
-        let line_width: f32 = 0.5;
+        let line_style = LineStyle::default();
         let fill_color = OfdColor {
             r: 156,
             g: 82,
@@ -852,8 +971,8 @@ mod tests {
         draw_abbreviate_path(
             &mut dt,
             &Point::new(0., 0.),
-            tokens,
-            &line_width,
+            commands,
+            &line_style,
             &fill_color,
             &stroke_color,
         );
@@ -1059,6 +1178,36 @@ mod tests {
         dt.write_png("test_draw_image.png").expect("save file failed");
     }
 
+    #[test]
+    fn test_clip_union_of_opposite_windings() {
+        let square = |x0: f32, x1: f32| ClipShape {
+            transform: Ctm::identity(),
+            commands: vec![
+                PathCommand::MoveTo(x0, 0.),
+                PathCommand::LineTo(x1, 0.),
+                PathCommand::LineTo(x1, 10.),
+                PathCommand::LineTo(x0, 10.),
+                PathCommand::Close,
+            ],
+            fill_rule: FillRule::NonZero,
+        };
+        // the second square runs the other way round, overlapping the first one
+        let clip = ResolvedClip { shapes: vec![square(0., 10.), square(15., 5.)] };
+        let mut backend = RaqoteDrawBackend {
+            dt: DrawTarget::new(20, 10),
+            clip_masks: Vec::new(),
+            pattern_tiles: Vec::new(),
+            options: RenderOptions::default(),
+        };
+        backend.push_clip(&clip);
+        backend.dt.fill_rect(0., 0., 20., 10., &Source::Solid(SolidSource { r: 0, g: 0, b: 0, a: 0xff }), &DrawOptions::new());
+        backend.pop_clip();
+
+        let alpha = |x: usize| backend.dt.get_data()[5 * 20 + x] >> 24;
+        assert_eq!((alpha(2), alpha(7), alpha(12)), (0xff, 0xff, 0xff));
+        assert_eq!(alpha(17), 0);
+    }
+
     use sw_composite::{over, over_exact};
 
     fn over_(src: u32, dst: u32) -> u32 {
@@ -0,0 +1,480 @@
+//! Backend independent shadings.
+//!
+//! `AxialShd` and `RadialShd` are resolved to plain two point gradients: `MapType` and
+//! `Extend` are folded into the colour stops, so a backend only has to clamp at the ends.
+//! Gouraud meshes are rasterised to an image, clamped the same way.
+
+use image::{Rgba, RgbaImage};
+
+use crate::node_draw::{ofd_color, OfdColor, PPMM};
+use crate::ofd::{AxialShd, GouraudPoint, GouraudShd, LaGouraudShd, MapType, RadialShd, Segment};
+use crate::style::Ctm;
+
+/// Repeated or reflected gradients are expanded to at most this many periods.
+const MAX_MAP_PERIODS: usize = 256;
+/// Fraction of the axis added beyond an end which doesn't extend, transparent from a hard
+/// stop at the end. Backends clamp at the ends, so any length covers everything beyond.
+const NO_EXTEND_PAD: f32 = 1.;
+/// Largest width or height of a rasterised mesh, in pixels.
+const MAX_MESH_SIZE: f32 = 2048.;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GradientStop {
+    pub offset: f32,
+    pub color: OfdColor,
+}
+
+#[derive(Debug, Clone)]
+pub enum Shading {
+    /// Colours along the line from `start` to `end`, constant on its perpendiculars.
+    Axial {
+        start: (f32, f32),
+        end: (f32, f32),
+        stops: Vec<GradientStop>,
+    },
+    /// Colours interpolated from the `start` circle to the `end` circle, `transform`
+    /// maps the circles to the ellipses of the shading.
+    Radial {
+        start: (f32, f32),
+        start_radius: f32,
+        end: (f32, f32),
+        end_radius: f32,
+        transform: Ctm,
+        stops: Vec<GradientStop>,
+    },
+    /// A rasterised triangle mesh, `transform` maps the pixels to object space. Its
+    /// edge pixels hold the colour beyond the mesh.
+    Mesh {
+        image: RgbaImage,
+        transform: Ctm,
+    },
+}
+
+fn lerp_color(from: OfdColor, to: OfdColor, t: f32) -> OfdColor {
+    let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+    OfdColor {
+        r: lerp(from.r, to.r),
+        g: lerp(from.g, to.g),
+        b: lerp(from.b, to.b),
+        a: lerp(from.a, to.a),
+    }
+}
+
+/// The colour at `offset` of sorted, non empty stops, clamped at the ends.
+pub fn color_at(stops: &[GradientStop], offset: f32) -> OfdColor {
+    match stops.iter().position(|stop| stop.offset > offset) {
+        None => stops[stops.len() - 1].color,
+        Some(0) => stops[0].color,
+        Some(i) => {
+            let (from, to) = (&stops[i - 1], &stops[i]);
+            lerp_color(from.color, to.color, (offset - from.offset) / (to.offset - from.offset))
+        }
+    }
+}
+
+/// The colour stops of `segments`, positions left out are spread evenly.
+fn segment_stops(segments: &[Segment]) -> Vec<GradientStop> {
+    let last = segments.len().saturating_sub(1).max(1) as f32;
+    let mut stops: Vec<GradientStop> = segments.iter().enumerate()
+        .filter_map(|(i, segment)| Some(GradientStop {
+            offset: segment.position.unwrap_or(i as f32 / last).clamp(0., 1.),
+            color: ofd_color(&segment.color)?,
+        }))
+        .collect();
+    stops.sort_by(|a, b| a.offset.total_cmp(&b.offset));
+    stops
+}
+
+/// Repeats, or reflects every other time, the stops each `map_unit` along an axis of
+/// `axis_length`, ending on the colour reached at the end of the axis.
+fn map_stops(stops: Vec<GradientStop>, map_type: MapType, map_unit: Option<f32>, axis_length: f32) -> Vec<GradientStop> {
+    let period = match map_unit {
+        Some(map_unit) if map_type != MapType::Direct && map_unit > 0. && axis_length > 0. => map_unit / axis_length,
+        _ => return stops,
+    };
+    let periods = ((1. / period).ceil() as usize).clamp(1, MAX_MAP_PERIODS);
+    let mut mapped = Vec::new();
+    for k in 0..periods {
+        let reflected = map_type == MapType::Reflect && k % 2 == 1;
+        let local = |offset: f32| if reflected { 1. - offset } else { offset };
+        let mut period_stops: Vec<GradientStop> = stops.iter()
+            .map(|stop| GradientStop { offset: local(stop.offset), color: stop.color })
+            .collect();
+        if reflected {
+            period_stops.reverse();
+        }
+        // the colours at the period bounds, so repeats start over with a hard stop
+        mapped.push(GradientStop { offset: k as f32 * period, color: color_at(&period_stops, 0.) });
+        for stop in &period_stops {
+            mapped.push(GradientStop { offset: (k as f32 + stop.offset) * period, color: stop.color });
+        }
+        mapped.push(GradientStop { offset: (k + 1) as f32 * period, color: color_at(&period_stops, 1.) });
+    }
+    let end = color_at(&mapped, 1.);
+    mapped.retain(|stop| stop.offset < 1.);
+    mapped.push(GradientStop { offset: 1., color: end });
+    mapped
+}
+
+/// Pads the stops with the fractions of the axis `before` the start and `after` the end,
+/// each made transparent by a hard stop at the end unless it's 0.
+fn pad_stops(stops: &mut Vec<GradientStop>, before: f32, after: f32) {
+    let first = color_at(stops, 0.);
+    let last = color_at(stops, 1.);
+    stops.retain(|stop| stop.offset > 0. && stop.offset < 1.);
+    stops.insert(0, GradientStop { offset: 0., color: first });
+    stops.push(GradientStop { offset: 1., color: last });
+
+    for stop in stops.iter_mut() {
+        stop.offset = (stop.offset + before) / (1. + before + after);
+    }
+    if before > 0. {
+        stops.insert(0, GradientStop { offset: stops[0].offset, color: OfdColor { a: 0, ..first } });
+    }
+    if after > 0. {
+        let offset = stops[stops.len() - 1].offset;
+        stops.push(GradientStop { offset, color: OfdColor { a: 0, ..last } });
+    }
+}
+
+impl AxialShd {
+    pub fn resolve(&self) -> Option<Shading> {
+        let stops = segment_stops(&self.segment);
+        if stops.is_empty() {
+            log::warn!("AxialShd without any valid Segment");
+            return None;
+        }
+        let ((x0, y0), (x1, y1)) = (self.start_point, self.end_point);
+        let mut stops = map_stops(stops, self.map_type, self.map_unit, (x1 - x0).hypot(y1 - y0));
+        let before = if self.extend & 1 != 0 { 0. } else { NO_EXTEND_PAD };
+        let after = if self.extend & 2 != 0 { 0. } else { NO_EXTEND_PAD };
+        pad_stops(&mut stops, before, after);
+        let at = |t: f32| (x0 + (x1 - x0) * t, y0 + (y1 - y0) * t);
+        Some(Shading::Axial {
+            start: at(-before),
+            end: at(1. + after),
+            stops,
+        })
+    }
+}
+
+impl RadialShd {
+    pub fn resolve(&self) -> Option<Shading> {
+        let stops = segment_stops(&self.segment);
+        if stops.is_empty() {
+            log::warn!("RadialShd without any valid Segment");
+            return None;
+        }
+        let (r0, r1) = (self.start_radius, self.end_radius);
+        let mut stops = map_stops(stops, self.map_type, self.map_unit, (r1 - r0).abs());
+        let radius_at = |t: f32| r0 + (r1 - r0) * t;
+        // circles shrink to a point at most, past that there's nothing to make transparent
+        let before = match self.extend & 1 != 0 {
+            true => 0.,
+            false if r1 > r0 => NO_EXTEND_PAD.min(r0 / (r1 - r0)),
+            false => NO_EXTEND_PAD,
+        };
+        let after = match self.extend & 2 != 0 {
+            true => 0.,
+            false if r1 < r0 => NO_EXTEND_PAD.min(r1 / (r0 - r1)),
+            false => NO_EXTEND_PAD,
+        };
+        pad_stops(&mut stops, before, after);
+
+        let (x0, y0) = self.start_point;
+        let transform = if self.eccentricity > 0. && self.eccentricity < 1. {
+            Ctm::translate(-x0, -y0)
+                .then(&Ctm::scale(1., (1. - self.eccentricity.powi(2)).sqrt()))
+                .then(&Ctm::rotate(self.angle))
+                .then(&Ctm::translate(x0, y0))
+        } else {
+            Ctm::identity()
+        };
+        // the end centre in the space of the circles
+        let (x1, y1) = transform.invert()
+            .map_or(self.end_point, |t| t.transform_point(self.end_point.0, self.end_point.1));
+        let at = |t: f32| (x0 + (x1 - x0) * t, y0 + (y1 - y0) * t);
+        Some(Shading::Radial {
+            start: at(-before),
+            start_radius: radius_at(-before),
+            end: at(1. + after),
+            end_radius: radius_at(1. + after),
+            transform,
+            stops,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Vertex {
+    x: f32,
+    y: f32,
+    color: OfdColor,
+}
+
+impl From<&GouraudPoint> for Vertex {
+    fn from(point: &GouraudPoint) -> Self {
+        Vertex {
+            x: point.x,
+            y: point.y,
+            color: ofd_color(&point.color).unwrap_or_default(),
+        }
+    }
+}
+
+/// Rasterises the triangles with their vertex colours interpolated, at the output
+/// resolution. Pixels outside the triangles, including a one pixel border, get `background`.
+fn rasterise_mesh(triangles: &[[Vertex; 3]], background: Option<OfdColor>) -> Option<Shading> {
+    let vertices = || triangles.iter().flatten();
+    let min_x = vertices().map(|v| v.x).fold(f32::INFINITY, f32::min);
+    let min_y = vertices().map(|v| v.y).fold(f32::INFINITY, f32::min);
+    let max_x = vertices().map(|v| v.x).fold(f32::NEG_INFINITY, f32::max);
+    let max_y = vertices().map(|v| v.y).fold(f32::NEG_INFINITY, f32::max);
+    if !(max_x > min_x && max_y > min_y) {
+        return None;
+    }
+    let scale = PPMM
+        .min(MAX_MESH_SIZE / (max_x - min_x))
+        .min(MAX_MESH_SIZE / (max_y - min_y));
+    let width = ((max_x - min_x) * scale).ceil() as u32 + 2;
+    let height = ((max_y - min_y) * scale).ceil() as u32 + 2;
+    let background = background.map_or(Rgba([0, 0, 0, 0]), |c| Rgba([c.r, c.g, c.b, c.a]));
+    let mut image = RgbaImage::from_pixel(width, height, background);
+
+    let to_pixel = |v: &Vertex| ((v.x - min_x) * scale + 1., (v.y - min_y) * scale + 1.);
+    for [v0, v1, v2] in triangles {
+        let (p0, p1, p2) = (to_pixel(v0), to_pixel(v1), to_pixel(v2));
+        let area = (p1.0 - p0.0) * (p2.1 - p0.1) - (p2.0 - p0.0) * (p1.1 - p0.1);
+        if area.abs() < f32::EPSILON {
+            continue;
+        }
+        let x0 = p0.0.min(p1.0).min(p2.0).floor().max(0.) as u32;
+        let y0 = p0.1.min(p1.1).min(p2.1).floor().max(0.) as u32;
+        let x1 = (p0.0.max(p1.0).max(p2.0).ceil() as u32).min(width);
+        let y1 = (p0.1.max(p1.1).max(p2.1).ceil() as u32).min(height);
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+                let w0 = ((p1.0 - px) * (p2.1 - py) - (p2.0 - px) * (p1.1 - py)) / area;
+                let w1 = ((p2.0 - px) * (p0.1 - py) - (p0.0 - px) * (p2.1 - py)) / area;
+                let w2 = 1. - w0 - w1;
+                if w0 < -1e-4 || w1 < -1e-4 || w2 < -1e-4 {
+                    continue;
+                }
+                let channel = |c: fn(&OfdColor) -> u8| {
+                    (w0 * c(&v0.color) as f32 + w1 * c(&v1.color) as f32 + w2 * c(&v2.color) as f32)
+                        .round().clamp(0., 255.) as u8
+                };
+                image.put_pixel(x, y, Rgba([
+                    channel(|c| c.r),
+                    channel(|c| c.g),
+                    channel(|c| c.b),
+                    channel(|c| c.a),
+                ]));
+            }
+        }
+    }
+
+    Some(Shading::Mesh {
+        image,
+        transform: Ctm::translate(-1., -1.)
+            .then(&Ctm::scale(1. / scale, 1. / scale))
+            .then(&Ctm::translate(min_x, min_y)),
+    })
+}
+
+/// The colour beyond a mesh, `BackColor` when it extends.
+fn mesh_background(extend: u8, back_color: Option<&crate::ofd::_Color>) -> Option<OfdColor> {
+    if extend == 0 {
+        return None;
+    }
+    back_color.and_then(ofd_color)
+}
+
+impl GouraudShd {
+    pub fn resolve(&self) -> Option<Shading> {
+        // each point with EdgeFlag 0 starts a triangle with the next two points, 1 and 2
+        // make a triangle with the last edge, or the last two vertices, of the previous one
+        let points: Vec<(u8, Vertex)> = self.point.iter()
+            .map(|point| (point.edge_flag.unwrap_or(0), Vertex::from(point)))
+            .collect();
+        let mut triangles: Vec<[Vertex; 3]> = Vec::new();
+        let mut i = 0;
+        while i < points.len() {
+            let (flag, vertex) = points[i];
+            match (flag, triangles.last()) {
+                (1, Some(&[_, vb, vc])) => {
+                    triangles.push([vb, vc, vertex]);
+                    i += 1;
+                }
+                (2, Some(&[va, _, vc])) => {
+                    triangles.push([va, vc, vertex]);
+                    i += 1;
+                }
+                _ if i + 2 < points.len() => {
+                    triangles.push([vertex, points[i + 1].1, points[i + 2].1]);
+                    i += 3;
+                }
+                _ => break,
+            }
+        }
+        if triangles.is_empty() {
+            log::warn!("GouraudShd with less than 3 points");
+            return None;
+        }
+        rasterise_mesh(&triangles, mesh_background(self.extend, self.back_color.as_ref()))
+    }
+}
+
+impl LaGouraudShd {
+    pub fn resolve(&self) -> Option<Shading> {
+        let columns = self.vertices_per_row;
+        let vertices: Vec<Vertex> = self.point.iter().map(Vertex::from).collect();
+        if columns < 2 || vertices.len() < 2 * columns {
+            log::warn!("LaGouraudShd needs at least 2 rows of 2 points, VerticesPerRow: {}", columns);
+            return None;
+        }
+        let rows = vertices.len() / columns;
+        let mut triangles = Vec::with_capacity((rows - 1) * (columns - 1) * 2);
+        for row in 0..rows - 1 {
+            for column in 0..columns - 1 {
+                let at = |row: usize, column: usize| vertices[row * columns + column];
+                let (a, b) = (at(row, column), at(row, column + 1));
+                let (c, d) = (at(row + 1, column), at(row + 1, column + 1));
+                triangles.push([a, b, c]);
+                triangles.push([b, d, c]);
+            }
+        }
+        rasterise_mesh(&triangles, mesh_background(self.extend, self.back_color.as_ref()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GradientStop, Shading};
+    use crate::ofd::{AxialShd, GouraudShd, LaGouraudShd, RadialShd};
+
+    fn stops(shading: &Shading) -> Vec<(f32, (u8, u8, u8, u8))> {
+        let stops: &Vec<GradientStop> = match shading {
+            Shading::Axial { stops, .. } | Shading::Radial { stops, .. } => stops,
+            Shading::Mesh { .. } => unreachable!(),
+        };
+        stops.iter().map(|s| (s.offset, (s.color.r, s.color.g, s.color.b, s.color.a))).collect()
+    }
+
+    #[test]
+    fn test_axial_extend() {
+        let axial_shd: AxialShd = quick_xml::de::from_str(
+            r#"<AxialShd StartPoint="0 0" EndPoint="64 0" Extend="1">
+                <Segment><Color Value="255 0 0"/></Segment>
+                <Segment><Color Value="0 0 255"/></Segment>
+            </AxialShd>"#
+        ).unwrap();
+        let shading = axial_shd.resolve().unwrap();
+        match shading {
+            Shading::Axial { start, end, .. } => assert_eq!((start, end), ((0., 0.), (128., 0.))),
+            _ => unreachable!(),
+        }
+        // transparent right from the end point
+        assert_eq!(stops(&shading), vec![
+            (0., (255, 0, 0, 255)),
+            (0.5, (0, 0, 255, 255)),
+            (0.5, (0, 0, 255, 0)),
+        ]);
+    }
+
+    #[test]
+    fn test_radial_no_extend() {
+        let radial_shd: RadialShd = quick_xml::de::from_str(
+            r#"<RadialShd StartPoint="0 0" StartRadius="5" EndPoint="0 0" EndRadius="25">
+                <Segment><Color Value="255 0 0"/></Segment>
+                <Segment><Color Value="0 0 255"/></Segment>
+            </RadialShd>"#
+        ).unwrap();
+        let shading = radial_shd.resolve().unwrap();
+        match shading {
+            // the start circle shrinks to its centre, the end circle grows by the axis
+            Shading::Radial { start_radius, end_radius, .. } => assert_eq!((start_radius, end_radius), (0., 45.)),
+            _ => unreachable!(),
+        }
+        let total = 0.25 + 1. + 1.;
+        assert_eq!(stops(&shading), vec![
+            (0.25 / total, (255, 0, 0, 0)),
+            (0.25 / total, (255, 0, 0, 255)),
+            (1.25 / total, (0, 0, 255, 255)),
+            (1.25 / total, (0, 0, 255, 0)),
+        ]);
+    }
+
+    #[test]
+    fn test_axial_reflect() {
+        let axial_shd: AxialShd = quick_xml::de::from_str(
+            r#"<AxialShd StartPoint="0 0" EndPoint="0 25" MapType="Reflect" MapUnit="10" Extend="3">
+                <Segment Position="0"><Color Value="0 0 0"/></Segment>
+                <Segment Position="1"><Color Value="200 200 200"/></Segment>
+            </AxialShd>"#
+        ).unwrap();
+        let stops: Vec<(f32, u8)> = stops(&axial_shd.resolve().unwrap()).into_iter()
+            .map(|(offset, (r, ..))| ((offset * 25.).round(), r))
+            .collect();
+        assert_eq!(stops.first(), Some(&(0., 0)));
+        assert!(stops.contains(&(10., 200)) && stops.contains(&(20., 0)));
+        // the axis ends half way through the third period
+        assert_eq!(stops.last(), Some(&(25., 100)));
+    }
+
+    /// The colour of the mesh at a point in object space.
+    fn mesh_color(shading: &Shading, x: f32, y: f32) -> [u8; 4] {
+        match shading {
+            Shading::Mesh { image, transform } => {
+                let (u, v) = transform.invert().unwrap().transform_point(x, y);
+                image.get_pixel(u as u32, v as u32).0
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_gouraud_mesh() {
+        // two triangles sharing an edge, the second one by EdgeFlag
+        let gouraud_shd: GouraudShd = quick_xml::de::from_str(
+            r#"<GouraudShd Extend="1">
+                <Point X="0" Y="0" EdgeFlag="0"><Color Value="255 0 0"/></Point>
+                <Point X="10" Y="0" EdgeFlag="0"><Color Value="255 0 0"/></Point>
+                <Point X="0" Y="10" EdgeFlag="0"><Color Value="0 0 255"/></Point>
+                <Point X="10" Y="10" EdgeFlag="1"><Color Value="0 0 255"/></Point>
+                <BackColor Value="0 255 0"/>
+            </GouraudShd>"#
+        ).unwrap();
+        let shading = gouraud_shd.resolve().unwrap();
+        let [r, g, b, a] = mesh_color(&shading, 5., 0.1);
+        assert!(r > 250 && g == 0 && b < 5 && a == 255);
+        let [r, g, b, a] = mesh_color(&shading, 9., 9.9);
+        assert!(r < 5 && g == 0 && b > 250 && a == 255);
+        let [r, g, b, _] = mesh_color(&shading, 2., 5.);
+        assert!(g == 0 && (100..155).contains(&r) && (100..155).contains(&b));
+        // the border holds the BackColor
+        assert_eq!(mesh_color(&shading, -0.1, -0.1), [0, 255, 0, 255]);
+    }
+
+    #[test]
+    fn test_la_gouraud_mesh() {
+        let la_gouraud_shd: LaGouraudShd = quick_xml::de::from_str(
+            r#"<LaGouraudShd VerticesPerRow="2">
+                <Point X="0" Y="0"><Color Value="0 0 0"/></Point>
+                <Point X="20" Y="0"><Color Value="200 0 0"/></Point>
+                <Point X="0" Y="10"><Color Value="0 0 0"/></Point>
+                <Point X="20" Y="10"><Color Value="200 0 0"/></Point>
+            </LaGouraudShd>"#
+        ).unwrap();
+        let shading = la_gouraud_shd.resolve().unwrap();
+        let [r, ..] = mesh_color(&shading, 10., 3.);
+        assert!((95..=105).contains(&r));
+        assert_eq!(mesh_color(&shading, -0.1, 5.), [0, 0, 0, 0]);
+
+        let la_gouraud_shd: LaGouraudShd = quick_xml::de::from_str(
+            r#"<LaGouraudShd VerticesPerRow="3"><Point X="0" Y="0"><Color Value="0 0 0"/></Point></LaGouraudShd>"#
+        ).unwrap();
+        assert!(la_gouraud_shd.resolve().is_none());
+    }
+}
@@ -2,17 +2,23 @@ use std::fs::File;
 use std::io::{Write};
 
 use skia_safe::path::ArcSize;
-use skia_safe::{Color, Data, EncodedImageFormat, Font, FontStyle, Image, Matrix, Paint, paint, PaintStyle, Path, PathDirection, Point, Rect, Surface, surfaces, TextBlob, Typeface};
+use skia_safe::{AlphaType, ClipOp, Color, ColorType, Data, EncodedImageFormat, FilterMode, Font, FontStyle, ImageInfo, images, Matrix, MipmapMode, Paint, paint, PaintStyle, Path, PathDirection, PathEffect, PathFillType, PathOp, Rect, SamplingOptions, Shader, Surface, surfaces, TextBlob, TileMode, Typeface};
 
-use crate::backends::{DrawBackend, Transform};
+use crate::backends::{DrawBackend, RenderOptions, Transform};
 use crate::backends::DrawError::OutputError;
-use crate::node_draw::{_PathToken, abbreviate_data, delta_to_vec, get_color_from_draw_param, MUTEX_IMAGE_PNG_RES, MUTEX_IMAGE_RES, MUTEX_RES_DRAW_PARAMS, ofd_color_from_v, OfdColor, PathToken, PPMM, RES_FONT_ID_MAP, Tag};
-use crate::ofd::{ImageObject, PathObject, PhysicalBox, TextObject};
+use crate::node_draw::{LineStyle, OfdColor, PathCommand, PPMM, RES_FONT_ID_MAP};
+use crate::ofd::{FillRule, ImageObject, LineCap, LineJoin, PhysicalBox, ReflectMethod};
+use crate::shading::{GradientStop, Shading};
+use crate::style::{Ctm, Fill, ResolvedClip, ResolvedPath, ResolvedText, TilingPattern};
+
+/// Largest width or height of a rendered pattern tile, in pixels.
+const MAX_TILE_SIZE: f32 = 2048.;
 
 pub struct SkiaBackend {
     pub surface: Surface,
     path: Path,
     paint: Paint,
+    options: RenderOptions,
 }
 
 impl From<Matrix> for Transform {
@@ -34,25 +40,18 @@ impl From<OfdColor> for Color {
     }
 }
 
-struct AdapterCtm(Option<String>);
-
-impl AdapterCtm {
-    fn to_matrix(&self) -> Matrix {
-        self.0.clone().map_or(
-            *Matrix::i(),
-            |s| {
-                let vec: Vec<f32> = s.split_whitespace().map(|s| s.parse().unwrap()).collect();
-                Matrix::new_all(
-                    vec[0], vec[2], vec[4],
-                    vec[1], vec[3], vec[5],
-                    0.0, 0.0, 1.0
-                )
-            })
+impl From<Ctm> for Matrix {
+    fn from(ctm: Ctm) -> Self {
+        Matrix::new_all(
+            ctm.a, ctm.c, ctm.e,
+            ctm.b, ctm.d, ctm.f,
+            0.0, 0.0, 1.0
+        )
     }
 }
 
 impl SkiaBackend {
-    pub fn new(width: i32, height: i32) -> Self {
+    pub fn new(width: i32, height: i32, options: RenderOptions) -> Self {
         let mut surface = surfaces::raster_n32_premul((width, height)).expect("surface");
         let path = Path::new();
         let mut paint = Paint::default();
@@ -65,8 +64,31 @@ impl SkiaBackend {
             surface,
             path,
             paint,
+            options,
+        }
+    }
+
+    /// Saves the canvas, drawing into a layer composited with `alpha` when it's translucent.
+    fn save_with_alpha(&mut self, alpha: u8) {
+        if alpha < 255 {
+            self.surface.canvas().save_layer_alpha(None, alpha.into());
+        } else {
+            self.surface.canvas().save();
         }
     }
+
+    /// A transparent backend for a pattern cell, `scale` maps its units to pixels.
+    fn new_cell(width: i32, height: i32, scale: (f32, f32), options: RenderOptions) -> Option<Self> {
+        let mut surface = surfaces::raster_n32_premul((width, height))?;
+        surface.canvas().clear(Color::TRANSPARENT);
+        surface.canvas().scale(scale);
+        Some(SkiaBackend {
+            surface,
+            path: Path::new(),
+            paint: Paint::default(),
+            options,
+        })
+    }
 }
 
 impl DrawBackend for SkiaBackend {
@@ -97,236 +119,279 @@ impl DrawBackend for SkiaBackend {
         self.surface.canvas().scale((PPMM, PPMM));
     }
 
+    fn concat(&mut self, transform: &Ctm) {
+        self.surface.canvas().concat(&(*transform).into());
+    }
+
     fn restore(&mut self, _transform: &Transform) {
         self.surface.canvas().restore();
     }
 
-    fn draw_path_object(&mut self, draw_param_id: Option<&String>, path_object: &PathObject) {
-        self.surface.canvas().save();
-        draw_path_object(&mut self.surface, draw_param_id, path_object);
+    fn draw_path(&mut self, path: &ResolvedPath) {
+        self.save_with_alpha(path.alpha);
+        draw_path(&mut self.surface, path, self.options);
         self.surface.canvas().restore();
     }
 
-    fn draw_text_object(&mut self, draw_param_id: Option<&String>, text_object: &TextObject) {
-        self.surface.canvas().save();
-        draw_text_object(&mut self.surface, draw_param_id, text_object);
+    fn draw_text(&mut self, text: &ResolvedText) {
+        self.save_with_alpha(text.alpha);
+        draw_text(&mut self.surface, text, self.options);
         self.surface.canvas().restore();
     }
 
-    fn draw_image_object(&mut self, image_object: &ImageObject) {
+    fn draw_image_object(&mut self, image_object: &ImageObject, layer_draw_param_id: Option<&String>) {
         // println!("draw_image_object: {:#?}", image_object);
-        let _id  = image_object.id.clone();
-        let img_file = MUTEX_IMAGE_RES.lock().unwrap().get(image_object.resource_id.as_str()).unwrap().clone();
-        let png_data = MUTEX_IMAGE_PNG_RES.lock().unwrap().get(&img_file).unwrap().clone();
-
-        let image = Image::from_encoded(Data::new_copy(png_data.as_slice())).unwrap();
+        let pixels = match image_object.resolve(layer_draw_param_id, self.options.use_image_substitutions) {
+            Some(pixels) => pixels,
+            None => return,
+        };
+        let info = ImageInfo::new(
+            (pixels.width() as i32, pixels.height() as i32),
+            ColorType::RGBA8888,
+            AlphaType::Unpremul,
+            None,
+        );
+        let image = match images::raster_from_data(&info, Data::new_copy(pixels.as_raw()), pixels.width() as usize * 4) {
+            Some(image) => image,
+            None => {
+                log::warn!("image {} of ImageObject {} not drawn", image_object.resource_id, image_object.id);
+                return;
+            }
+        };
         // println!("image: {:?}", image);
 
-        let boundary = image_object.boundary.clone();
+        let mut paint = Paint::default();
+        paint.set_alpha(image_object.alpha);
         self.surface.canvas().draw_image_rect(
             image,
             None,
-            Rect::from_point_and_size((boundary.x, boundary.y), (boundary.width, boundary.height)),
-            &Paint::default());
+            Rect::from_point_and_size((0., 0.), (1., 1.)),
+            &paint);
     }
-}
 
-fn draw_text_object(surface: &mut Surface, draw_param_id: Option<&String>, text_object: &TextObject) {
-    let (dp_fill_color, _dp_stroke_color) = get_color_from_draw_param(draw_param_id);
-
-    let boundary = text_object.boundary;
-    let font_id = text_object.font.clone();
-    let size: f32 = text_object.size;
-    let fill_color = text_object.fill_color.clone().map_or(
-        dp_fill_color.clone().unwrap_or(OfdColor::default()),
-        |s| match s.value {
-            None => dp_fill_color.clone().unwrap_or(OfdColor::default()),
-            Some(c) => ofd_color_from_v(&c)
-        }
-    );
-    let font = RES_FONT_ID_MAP.lock().unwrap().get(font_id.as_str()).unwrap().clone().take();
+    fn begin_group(&mut self, alpha: u8) {
+        self.surface.canvas().save_layer_alpha(None, alpha.into());
+    }
 
-    // println!("draw_text_object {:?}, {:?}: {:?}", &dp_fill_color, &fill_color, text_object);
+    fn end_group(&mut self) {
+        self.surface.canvas().restore();
+    }
 
-    let ctm: Matrix = AdapterCtm(text_object.ctm.clone()).to_matrix();
-    let text_code = text_object.text_code.clone();
-    let mut iter_delta_x = text_code.delta_x.map_or(
-        vec![0.; text_code.text.len()].into_iter(),
-        |s| delta_to_vec(&s).into_iter()
-    );
+    fn push_clip(&mut self, clip: &ResolvedClip) {
+        let mut area: Option<Path> = None;
+        for shape in &clip.shapes {
+            let path = build_path(&shape.commands, shape.fill_rule).with_transform(&shape.transform.into());
+            area = Some(match area {
+                None => path,
+                Some(area) => area.op(&path, PathOp::Union).unwrap_or(area),
+            });
+        }
+        self.surface.canvas().save();
+        if let Some(area) = area {
+            self.surface.canvas().clip_path(&area, ClipOp::Intersect, true);
+        }
+    }
 
-    let mut iter_delta_y = text_code.delta_y.map_or(
-        vec![0.; text_code.text.len()].into_iter(),
-        |s| delta_to_vec(&s).into_iter()
-    );
+    fn pop_clip(&mut self) {
+        self.surface.canvas().restore();
+    }
+}
 
-    let mut start_p = Point::new(text_code.x, text_code.y);
-    start_p.x += boundary.x;
-    start_p.y += boundary.y;
-    let mut positions = Vec::new();
-    let point_size = size;
+fn draw_text(surface: &mut Surface, text: &ResolvedText, options: RenderOptions) {
+    let fill = match &text.fill {
+        Some(fill) => fill,
+        None => return,
+    };
+    let font = match RES_FONT_ID_MAP.lock().unwrap().get(text.font.as_str()) {
+        Some(font) => font.clone().take(),
+        None => {
+            log::warn!("font {} not found, text not drawn", text.font);
+            return;
+        }
+    };
     let font = Font::from_typeface_with_params(
         Typeface::new(font.family_name(), FontStyle::default())
             .unwrap_or(Typeface::default()),
-        point_size, 1.0, 0.0,
+        text.size, 1.0, 0.0,
     );
 
     let mut paint = Paint::default();
     paint.set_anti_alias(true);
-    paint.set_color(fill_color);
+    set_fill(&mut paint, fill, options);
     paint.set_style(PaintStyle::Fill);
 
-    let mut pos = start_p.clone();
-
-    surface.canvas().translate((boundary.x, boundary.y));
-    surface.canvas().concat(&ctm);
-    surface.canvas().translate((-boundary.x, -boundary.y));
-
-    for c in text_code.text.chars() {
-        let mut text = String::new();
-        text.push(c);
-        positions.push(pos);
-        // println!("draw text: {} with {:?}", text, pos);
-        let blob = TextBlob::new(text, &font).unwrap();
+    surface.canvas().concat(&text.transform.into());
+    for glyph in &text.glyphs {
+        // println!("draw text: {} with {:?}", glyph.c, (glyph.x, glyph.y));
+        let blob = TextBlob::new(glyph.c.to_string(), &font).unwrap();
         surface.canvas().draw_text_blob(
             blob,
-            (pos.x, pos.y),
+            (glyph.x, glyph.y),
             &paint,
         );
+    }
+}
 
-        pos.x += iter_delta_x.next().unwrap_or(0.);
-        pos.y += iter_delta_y.next().unwrap_or(0.);
+fn build_path(commands: &[PathCommand], fill_rule: FillRule) -> Path {
+    let mut new_path = Path::new();
+    for command in commands {
+        match *command {
+            PathCommand::MoveTo(x, y) => {
+                new_path.move_to((x, y));
+            }
+            PathCommand::LineTo(x, y) => {
+                new_path.line_to((x, y));
+            }
+            PathCommand::QuadTo(x1, y1, x, y) => {
+                new_path.quad_to((x1, y1), (x, y));
+            }
+            PathCommand::CubicTo(x1, y1, x2, y2, x, y) => {
+                new_path.cubic_to((x1, y1), (x2, y2), (x, y));
+            }
+            PathCommand::ArcTo { rx, ry, x_axis_rotation, large_arc, sweep, x, y } => {
+                let large_arc = if large_arc { ArcSize::Large } else { ArcSize::Small };
+                let sweep = if sweep { PathDirection::CW } else { PathDirection::CCW };
+                new_path.arc_to_rotated((rx, ry), x_axis_rotation, large_arc, sweep, (x, y));
+            }
+            PathCommand::Close => {
+                new_path.close();
+            }
+        }
     }
+    new_path.set_fill_type(match fill_rule {
+        FillRule::NonZero => PathFillType::Winding,
+        FillRule::EvenOdd => PathFillType::EvenOdd,
+    });
+    new_path
 }
 
-fn draw_path_object(surface: &mut Surface, draw_param_id: Option<&String>, path_object: &PathObject) {
-    let draw_param = draw_param_id.map_or(
-        None,
-        |it| {
-            Some(MUTEX_RES_DRAW_PARAMS.lock().unwrap().get(it).unwrap().clone())
+fn draw_path(surface: &mut Surface, path: &ResolvedPath, options: RenderOptions) {
+    let new_path = build_path(&path.commands, path.fill_rule);
+
+    surface.canvas().concat(&path.transform.into());
+    if let Some(fill) = &path.fill {
+        let mut paint = Paint::default();
+        paint.set_anti_alias(true);
+        set_fill(&mut paint, fill, options);
+        paint.set_style(paint::Style::Fill);
+        surface.canvas().draw_path(&new_path, &paint);
+    }
+    if let Some(stroke) = &path.stroke {
+        let mut paint = Paint::default();
+        paint.set_anti_alias(true);
+        set_fill(&mut paint, stroke, options);
+        paint.set_style(paint::Style::Stroke);
+        apply_line_style(&mut paint, &path.line_style);
+        surface.canvas().draw_path(&new_path, &paint);
+    }
+}
+
+fn set_fill(paint: &mut Paint, fill: &Fill, options: RenderOptions) {
+    match fill {
+        Fill::Color(color) => {
+            paint.set_color(*color);
         }
-    );
-    let line_width: f32 = path_object.line_width.unwrap_or(0.5);
-    let boundary = &path_object.boundary;
-
-    // println!("draw_path_object: {:?}", path_object);
-    let path = abbreviate_data(&path_object.abbreviated_data);
-    // vec[0], -vec[1], -vec[2], vec[3], vec[4], vec[5],
-    let ctm: Matrix = AdapterCtm(path_object.ctm.clone()).to_matrix();
-
-    let stroke_color = path_object.stroke_color.clone().map_or(
-        draw_param.as_ref().map_or(
-            OfdColor::default(),
-            |dp| dp.stroke_color.clone().map_or(
-                OfdColor::default(),
-                |c| ofd_color_from_v(&c.value.unwrap_or("128 0 0".to_string()))
-            )
-        ),
-        |s| match s.value {
-            None => draw_param.as_ref().map_or(
-                OfdColor::default(),
-                |dp| dp.stroke_color.clone().map_or(
-                    OfdColor::default(),
-                    |c| ofd_color_from_v(&c.value.unwrap_or("128 0 0".to_string()))
-                )
-            ),
-            Some(c) => ofd_color_from_v(&c),
+        Fill::Shading(shading) => {
+            paint.set_shader(shading_shader(shading));
+        }
+        Fill::Pattern(pattern) => {
+            paint.set_shader(pattern_shader(pattern, options));
+            paint.set_alpha(pattern.alpha);
         }
+    }
+}
+
+/// Renders the cell of the pattern once, in a tile of the steps' size, and tiles it.
+fn pattern_shader(pattern: &TilingPattern, options: RenderOptions) -> Option<Shader> {
+    let width = (pattern.x_step * PPMM).min(MAX_TILE_SIZE).ceil().max(1.);
+    let height = (pattern.y_step * PPMM).min(MAX_TILE_SIZE).ceil().max(1.);
+    let scale = (width / pattern.x_step, height / pattern.y_step);
+    let mut cell = SkiaBackend::new_cell(width as i32, height as i32, scale, options)?;
+    cell.surface.canvas().clip_rect(Rect::from_wh(pattern.width, pattern.height), None, None);
+    pattern.cell.draw(&mut cell, None);
+
+    let tile_modes = match pattern.reflect_method {
+        ReflectMethod::Normal => (TileMode::Repeat, TileMode::Repeat),
+        ReflectMethod::Row => (TileMode::Mirror, TileMode::Repeat),
+        ReflectMethod::Column => (TileMode::Repeat, TileMode::Mirror),
+        ReflectMethod::RowAndColumn => (TileMode::Mirror, TileMode::Mirror),
+    };
+    let local_matrix = Matrix::concat(
+        &Matrix::from(pattern.transform),
+        &Matrix::scale((1. / scale.0, 1. / scale.1)),
     );
+    cell.surface.image_snapshot().to_shader(
+        tile_modes,
+        SamplingOptions::new(FilterMode::Linear, MipmapMode::None),
+        &local_matrix,
+    )
+}
 
-    surface.canvas().translate((boundary.x, boundary.y));
-    surface.canvas().concat(&ctm);
+fn gradient_colors(stops: &[GradientStop]) -> (Vec<Color>, Vec<f32>) {
+    stops.iter().map(|stop| (Color::from(stop.color), stop.offset)).unzip()
+}
 
-    let mut new_path = Path::new();
-    let mut idx = 0;
-    while idx < path.len() {
-        unsafe {
-            match path.get(idx) {
-                Some(PathToken {
-                         tag: Tag::C,
-                         token: _PathToken { op: 'M' },
-                     }) => {
-                    idx += 1;
-                    let x = path.get(idx).unwrap().token.v;
-                    idx += 1;
-                    let y = path.get(idx).unwrap().token.v;
-                    new_path.move_to((x, y));
-                }
-                Some(PathToken {
-                         tag: Tag::C,
-                         token: _PathToken { op: 'L' },
-                     }) => {
-                    let mut iter = path[idx + 1..idx + 3].iter().map(|pt| pt.token.v);
-                    idx += 2;
-                    let x = iter.next().unwrap();
-                    let y = iter.next().unwrap();
-                    new_path.line_to((x, y));
-                }
-                Some(PathToken {
-                         tag: Tag::C,
-                         token: _PathToken { op: 'B' },
-                     }) => {
-                    let mut iter = path[idx + 1..idx + 7].iter().map(|pt| pt.token.v);
-                    idx += 6;
-                    new_path.cubic_to(
-                        (iter.next().unwrap(), iter.next().unwrap()),
-                        (iter.next().unwrap(), iter.next().unwrap()),
-                        (iter.next().unwrap(), iter.next().unwrap()),
-                    );
-                }
-                Some(PathToken {
-                         tag: Tag::C,
-                         token: _PathToken { op: 'A' },
-                             }) => {
-                            let mut iter = path[idx + 1..idx + 8].iter().map(|pt| pt.token.v);
-                            idx += 7;
-                            let (rx, ry) = (iter.next().unwrap(), iter.next().unwrap());
-                            let x_axis_rotate = iter.next().unwrap();
-                            let large_arc = if (iter.next().unwrap() as u32) > 0 { ArcSize::Large } else { ArcSize::Small };
-                            let sweep = if (iter.next().unwrap() as i32) > 0 {PathDirection::CW} else {PathDirection::CCW};
-                            let (end_x, end_y) = (iter.next().unwrap(), iter.next().unwrap());
-
-                            new_path.arc_to_rotated((rx, ry), x_axis_rotate, large_arc, sweep, (end_x, end_y));
-                        }
-                Some(PathToken {
-                         tag: Tag::C,
-                         token: _PathToken { op: 'Q' },
-                     }) => {
-                    let mut iter = path[idx + 1..idx + 5].iter().map(|pt| pt.token.v);
-                    idx += 4;
-
-                    new_path.quad_to(
-                        (iter.next().unwrap(), iter.next().unwrap()),
-                        (iter.next().unwrap(), iter.next().unwrap()),
-                    );
-                }
-                Some(PathToken {
-                         tag: Tag::C,
-                         token: _PathToken { op: 'C' },
-                     }) => {}
-                Some(
-                    pt @ PathToken {
-                        tag: Tag::C,
-                        token: _,
-                    },
-                ) => {
-                    panic!("OFD path_token [{}] invalid!", pt);
-                }
-                Some(_) => {}
-                None => {}
-            }
+fn shading_shader(shading: &Shading) -> Option<Shader> {
+    match shading {
+        Shading::Axial { start, end, stops } => {
+            let (colors, offsets) = gradient_colors(stops);
+            Shader::linear_gradient(
+                (*start, *end),
+                colors.as_slice(),
+                offsets.as_slice(),
+                TileMode::Clamp,
+                None,
+                None,
+            )
+        }
+        Shading::Radial { start, start_radius, end, end_radius, transform, stops } => {
+            let (colors, offsets) = gradient_colors(stops);
+            Shader::two_point_conical_gradient(
+                *start,
+                *start_radius,
+                *end,
+                *end_radius,
+                colors.as_slice(),
+                offsets.as_slice(),
+                TileMode::Clamp,
+                None,
+                &Matrix::from(*transform),
+            )
+        }
+        Shading::Mesh { image, transform } => {
+            let info = ImageInfo::new(
+                (image.width() as i32, image.height() as i32),
+                ColorType::RGBA8888,
+                AlphaType::Unpremul,
+                None,
+            );
+            images::raster_from_data(&info, Data::new_copy(image.as_raw()), image.width() as usize * 4)?
+                .to_shader(
+                    (TileMode::Clamp, TileMode::Clamp),
+                    SamplingOptions::new(FilterMode::Linear, MipmapMode::None),
+                    &Matrix::from(*transform),
+                )
         }
-        idx += 1;
     }
-    new_path.close();
-    let mut paint = Paint::default();
-    paint.set_anti_alias(true);
-    paint.set_stroke_width(line_width);
-    paint.set_color(stroke_color);
-    paint.set_style(paint::Style::Stroke);
-    surface.canvas().draw_path(&new_path, &paint);
 }
 
+fn apply_line_style(paint: &mut Paint, line_style: &LineStyle) {
+    paint.set_stroke_width(line_style.width);
+    paint.set_stroke_join(match line_style.join {
+        LineJoin::Miter => paint::Join::Miter,
+        LineJoin::Round => paint::Join::Round,
+        LineJoin::Bevel => paint::Join::Bevel,
+    });
+    paint.set_stroke_cap(match line_style.cap {
+        LineCap::Butt => paint::Cap::Butt,
+        LineCap::Round => paint::Cap::Round,
+        LineCap::Square => paint::Cap::Square,
+    });
+    paint.set_stroke_miter(line_style.miter_limit);
+    if !line_style.dash_pattern.is_empty() {
+        paint.set_path_effect(PathEffect::dash(&line_style.dash_intervals(), line_style.dash_offset));
+    }
+}
 
 #[cfg(test)]
 mod tests {
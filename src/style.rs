@@ -0,0 +1,665 @@
+//! Backend independent resolution of graphic units.
+//!
+//! Draw params, inline attributes and their defaults are resolved here once, so a
+//! `DrawBackend` only has to paint what it's given and every backend renders alike.
+
+use std::sync::Arc;
+
+use font_kit::hinting::HintingOptions;
+use font_kit::outline::{OutlineBuilder, PointFlags};
+use image::imageops::{self, FilterType};
+use image::{Pixel, Rgba, RgbaImage};
+
+use crate::node_draw::{delta_to_vec, get_draw_param, mul_alpha, ofd_color, path_commands, LineStyle, OfdColor, PathCommand, MUTEX_IMAGE_RES, MUTEX_RGB_IMAGE_RES, MUTEX_STENCIL_IMAGES, RES_FONT_ID_MAP};
+use crate::ofd::{_Color, Clip, ClipArea, FillRule, ImageObject, PageBlock, PathObject, Pattern, PhysicalBox, ReflectMethod, RelativeTo, TextObject};
+use crate::shading::Shading;
+
+/// An OFD transform matrix `a b c d e f`, mapping `(x, y)` to
+/// `(a * x + c * y + e, b * x + d * y + f)`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ctm {
+    pub a: f32,
+    pub b: f32,
+    pub c: f32,
+    pub d: f32,
+    pub e: f32,
+    pub f: f32,
+}
+
+impl Default for Ctm {
+    fn default() -> Self {
+        Ctm::identity()
+    }
+}
+
+impl Ctm {
+    pub fn identity() -> Self {
+        Ctm { a: 1., b: 0., c: 0., d: 1., e: 0., f: 0. }
+    }
+
+    pub fn translate(x: f32, y: f32) -> Self {
+        Ctm { e: x, f: y, ..Ctm::identity() }
+    }
+
+    pub fn scale(sx: f32, sy: f32) -> Self {
+        Ctm { a: sx, d: sy, ..Ctm::identity() }
+    }
+
+    /// Rotation by `degrees`, counterclockwise in a y-up space (clockwise on the page).
+    pub fn rotate(degrees: f32) -> Self {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        Ctm { a: cos, b: sin, c: -sin, d: cos, ..Ctm::identity() }
+    }
+
+    /// Parses the six numbers of a `CTM` attribute, `None` if it's malformed.
+    pub fn from_v(s: &str) -> Option<Self> {
+        let v: Vec<f32> = s.split_whitespace().map(|s| s.parse().ok()).collect::<Option<_>>()?;
+        match v[..] {
+            [a, b, c, d, e, f] => Some(Ctm { a, b, c, d, e, f }),
+            _ => None,
+        }
+    }
+
+    /// The transform of an optional `CTM` attribute, identity if it's absent or malformed.
+    pub fn from_attribute(ctm: Option<&String>) -> Self {
+        ctm.map_or(Ctm::identity(), |s| Ctm::from_v(s).unwrap_or_else(|| {
+            log::warn!("CTM [{}] invalid, identity used", s);
+            Ctm::identity()
+        }))
+    }
+
+    /// The transform applying `self` first and `other` afterwards.
+    pub fn then(&self, other: &Ctm) -> Ctm {
+        Ctm {
+            a: other.a * self.a + other.c * self.b,
+            b: other.b * self.a + other.d * self.b,
+            c: other.a * self.c + other.c * self.d,
+            d: other.b * self.c + other.d * self.d,
+            e: other.a * self.e + other.c * self.f + other.e,
+            f: other.b * self.e + other.d * self.f + other.f,
+        }
+    }
+
+    pub fn invert(&self) -> Option<Ctm> {
+        let det = self.a * self.d - self.b * self.c;
+        if det.abs() < f32::EPSILON {
+            return None;
+        }
+        Some(Ctm {
+            a: self.d / det,
+            b: -self.b / det,
+            c: -self.c / det,
+            d: self.a / det,
+            e: (self.c * self.f - self.d * self.e) / det,
+            f: (self.b * self.e - self.a * self.f) / det,
+        })
+    }
+
+    pub fn transform_point(&self, x: f32, y: f32) -> (f32, f32) {
+        (self.a * x + self.c * y + self.e, self.b * x + self.d * y + self.f)
+    }
+}
+
+/// Maps the object space of a graphic unit to its parent: the `CTM` is applied
+/// first, then the result is placed at the origin of the `Boundary`.
+pub fn object_transform(ctm: Option<&String>, boundary_x: f32, boundary_y: f32) -> Ctm {
+    Ctm::from_attribute(ctm)
+        .then(&Ctm::translate(boundary_x, boundary_y))
+}
+
+/// Maps the unit square an image is drawn in, top row at `y = 0`, through the image `CTM`
+/// into the boundary. Without a `CTM` the image is stretched over the boundary.
+pub fn image_transform(ctm: Option<&String>, boundary: &PhysicalBox) -> Ctm {
+    match ctm {
+        Some(_) => object_transform(ctm, boundary.x, boundary.y),
+        None => Ctm::scale(boundary.width, boundary.height)
+            .then(&Ctm::translate(boundary.x, boundary.y)),
+    }
+}
+
+/// How the area of a path or glyph is painted.
+#[derive(Debug, Clone)]
+pub enum Fill {
+    Color(OfdColor),
+    Shading(Shading),
+    Pattern(Box<TilingPattern>),
+}
+
+/// A `Pattern` fill: `cell` clipped to `width` by `height`, repeated every `x_step` and
+/// `y_step`, mirrored along the rows and/or columns by `reflect_method`.
+#[derive(Debug, Clone)]
+pub struct TilingPattern {
+    /// Shared by the resolutions of the same `Pattern`.
+    pub cell: Arc<PageBlock>,
+    pub width: f32,
+    pub height: f32,
+    pub x_step: f32,
+    pub y_step: f32,
+    pub reflect_method: ReflectMethod,
+    /// Maps the pattern space to the object space of the filled object.
+    pub transform: Ctm,
+    pub alpha: u8,
+}
+
+impl Pattern {
+    /// Resolves the pattern filling an object mapped to the page by `object_transform`.
+    fn resolve(&self, object_transform: &Ctm) -> Option<TilingPattern> {
+        let x_step = self.x_step.unwrap_or(self.width);
+        let y_step = self.y_step.unwrap_or(self.height);
+        if !(x_step > 0. && y_step > 0. && self.width > 0. && self.height > 0.) {
+            log::warn!("Pattern with an empty cell: {}x{} every {}, {}", self.width, self.height, x_step, y_step);
+            return None;
+        }
+        let ctm = Ctm::from_attribute(self.ctm.as_ref());
+        let transform = match self.relative_to {
+            RelativeTo::Object => ctm,
+            RelativeTo::Page => ctm.then(&object_transform.invert()?),
+        };
+        Some(TilingPattern {
+            cell: self.cell_content.clone(),
+            width: self.width,
+            height: self.height,
+            x_step,
+            y_step,
+            reflect_method: self.reflect_method,
+            transform,
+            alpha: 255,
+        })
+    }
+}
+
+impl Default for Fill {
+    fn default() -> Self {
+        Fill::Color(OfdColor::default())
+    }
+}
+
+impl Fill {
+    /// The fill with its opacity scaled by `alpha`.
+    fn faded(mut self, alpha: u8) -> Fill {
+        if alpha == 255 {
+            return self;
+        }
+        match &mut self {
+            Fill::Color(color) => color.a = mul_alpha(color.a, alpha),
+            Fill::Shading(Shading::Axial { stops, .. }) | Fill::Shading(Shading::Radial { stops, .. }) => {
+                for stop in stops {
+                    stop.color.a = mul_alpha(stop.color.a, alpha);
+                }
+            }
+            Fill::Shading(Shading::Mesh { image, .. }) => {
+                for pixel in image.pixels_mut() {
+                    pixel.0[3] = mul_alpha(pixel.0[3], alpha);
+                }
+            }
+            Fill::Pattern(pattern) => pattern.alpha = mul_alpha(pattern.alpha, alpha),
+        }
+        self
+    }
+}
+
+/// The paint of a colour: its pattern or shading if it has one, else its value.
+fn fill_of(color: &_Color, object_transform: &Ctm) -> Option<Fill> {
+    let fill = if let Some(pattern) = &color.pattern {
+        pattern.resolve(object_transform).map(|p| Fill::Pattern(Box::new(p)))
+    } else if let Some(axial_shd) = &color.axial_shd {
+        axial_shd.resolve().map(Fill::Shading)
+    } else if let Some(radial_shd) = &color.radial_shd {
+        radial_shd.resolve().map(Fill::Shading)
+    } else if let Some(gouraud_shd) = &color.gouraud_shd {
+        gouraud_shd.resolve().map(Fill::Shading)
+    } else if let Some(la_gouraud_shd) = &color.la_gouraud_shd {
+        la_gouraud_shd.resolve().map(Fill::Shading)
+    } else {
+        // the alpha of a plain colour is applied with its value
+        return ofd_color(color).map(Fill::Color);
+    };
+    fill.map(|fill| fill.faded(color.alpha))
+}
+
+/// Resolves the paint of an object mapped to the page by `object_transform`, its own
+/// colour wins over the draw param's.
+pub fn resolve_fill(color: Option<&_Color>, draw_param_color: Option<&_Color>, object_transform: &Ctm) -> Option<Fill> {
+    color.and_then(|c| fill_of(c, object_transform))
+        .or_else(|| draw_param_color.and_then(|c| fill_of(c, object_transform)))
+}
+
+/// A `PathObject` ready to be painted, in object space mapped by `transform`.
+#[derive(Debug, Clone)]
+pub struct ResolvedPath {
+    pub transform: Ctm,
+    pub commands: Vec<PathCommand>,
+    pub fill_rule: FillRule,
+    /// `None` when the path isn't filled.
+    pub fill: Option<Fill>,
+    /// `None` when the path isn't stroked.
+    pub stroke: Option<Fill>,
+    pub line_style: LineStyle,
+    /// Opacity of the fill and stroke together.
+    pub alpha: u8,
+}
+
+/// A character of a `TextCode` at its position in object space.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Glyph {
+    pub c: char,
+    pub x: f32,
+    pub y: f32,
+}
+
+/// A `TextObject` ready to be painted, in object space mapped by `transform`.
+#[derive(Debug, Clone)]
+pub struct ResolvedText {
+    pub transform: Ctm,
+    pub font: String,
+    pub size: f32,
+    pub fill: Option<Fill>,
+    pub glyphs: Vec<Glyph>,
+    pub alpha: u8,
+}
+
+impl PathObject {
+    pub fn resolve(&self, layer_draw_param_id: Option<&String>) -> ResolvedPath {
+        let draw_param = get_draw_param(layer_draw_param_id, self.draw_param.as_ref());
+        let transform = object_transform(self.ctm.as_ref(), self.boundary.x, self.boundary.y);
+        let fill = self.fill.then(|| {
+            resolve_fill(
+                self.fill_color.as_ref(),
+                draw_param.as_ref().and_then(|dp| dp.fill_color.as_ref()),
+                &transform,
+            ).unwrap_or_default()
+        });
+        let stroke = self.stroke.then(|| {
+            resolve_fill(
+                self.stroke_color.as_ref(),
+                draw_param.as_ref().and_then(|dp| dp.stroke_color.as_ref()),
+                &transform,
+            ).unwrap_or_default()
+        });
+        ResolvedPath {
+            transform,
+            commands: path_commands(&self.abbreviated_data),
+            fill_rule: self.rule,
+            fill,
+            stroke,
+            line_style: LineStyle::from_path_object(self, draw_param.as_ref()),
+            alpha: self.alpha,
+        }
+    }
+}
+
+impl TextObject {
+    pub fn resolve(&self, layer_draw_param_id: Option<&String>) -> ResolvedText {
+        let draw_param = get_draw_param(layer_draw_param_id, self.draw_param.as_ref());
+        let transform = object_transform(self.ctm.as_ref(), self.boundary.x, self.boundary.y);
+        let fill = self.fill.then(|| {
+            resolve_fill(
+                self.fill_color.as_ref(),
+                draw_param.as_ref().and_then(|dp| dp.fill_color.as_ref()),
+                &transform,
+            ).unwrap_or_default()
+        });
+
+        let text_code = &self.text_code;
+        let mut iter_delta_x = text_code.delta_x.as_ref()
+            .map_or(vec![], delta_to_vec)
+            .into_iter();
+        let mut iter_delta_y = text_code.delta_y.as_ref()
+            .map_or(vec![], delta_to_vec)
+            .into_iter();
+        let (mut x, mut y) = (text_code.x, text_code.y);
+        let mut glyphs = Vec::new();
+        for c in text_code.text.chars() {
+            glyphs.push(Glyph { c, x, y });
+            x += iter_delta_x.next().unwrap_or(0.);
+            y += iter_delta_y.next().unwrap_or(0.);
+        }
+
+        ResolvedText {
+            transform,
+            font: self.font.clone(),
+            size: self.size,
+            fill,
+            glyphs,
+            alpha: self.alpha,
+        }
+    }
+}
+
+/// The pixels of an image resource, and whether they're a stencil.
+fn image_res(resource_id: &str) -> Option<(RgbaImage, bool)> {
+    let file = MUTEX_IMAGE_RES.lock().unwrap().get(resource_id).cloned()?;
+    let image = MUTEX_RGB_IMAGE_RES.lock().unwrap().get(&file).cloned()?;
+    Some((image, MUTEX_STENCIL_IMAGES.lock().unwrap().get(&file) == Some(&true)))
+}
+
+impl ImageObject {
+    /// The pixels of the image, its `Substitution` if `use_substitution`, with the
+    /// luminance of the `ImageMask` multiplied into the alpha. Stencils are coloured with
+    /// the fill colour of the draw param, black by default. `None` if the image isn't
+    /// loaded.
+    pub fn resolve(&self, layer_draw_param_id: Option<&String>, use_substitution: bool) -> Option<RgbaImage> {
+        let substitution = self.substitution.as_deref()
+            .filter(|_| use_substitution)
+            .and_then(image_res);
+        let (mut image, stencil) = match substitution.or_else(|| image_res(&self.resource_id)) {
+            Some(image) => image,
+            None => {
+                log::warn!("image {} of ImageObject {} not found", self.resource_id, self.id);
+                return None;
+            }
+        };
+        if stencil {
+            let color = get_draw_param(layer_draw_param_id, self.draw_param.as_ref())
+                .and_then(|dp| dp.fill_color)
+                .and_then(|color| ofd_color(&color))
+                .unwrap_or_default();
+            for p in image.pixels_mut() {
+                *p = Rgba([color.r, color.g, color.b, mul_alpha(p[3], color.a)]);
+            }
+        }
+        if let Some(mask_id) = &self.image_mask {
+            match image_res(mask_id) {
+                Some((mut mask, _)) => {
+                    if mask.dimensions() != image.dimensions() {
+                        mask = imageops::resize(&mask, image.width(), image.height(), FilterType::Triangle);
+                    }
+                    for (p, m) in image.pixels_mut().zip(mask.pixels()) {
+                        let coverage = m.to_luma()[0] as u32 * m[3] as u32 / 255;
+                        p[3] = (p[3] as u32 * coverage / 255) as u8;
+                    }
+                }
+                None => log::warn!("image mask {} of ImageObject {} not found", mask_id, self.id),
+            }
+        }
+        Some(image)
+    }
+}
+
+/// A shape of a clip, mapped to the parent space of the clipped unit by `transform`.
+#[derive(Debug, Clone)]
+pub struct ClipShape {
+    pub transform: Ctm,
+    pub commands: Vec<PathCommand>,
+    pub fill_rule: FillRule,
+}
+
+/// A `Clip` ready to be applied: the union of its shapes.
+#[derive(Debug, Clone)]
+pub struct ResolvedClip {
+    pub shapes: Vec<ClipShape>,
+}
+
+impl Clip {
+    /// Resolves the clip of a unit placed at `boundary`, `None` if none of its areas
+    /// has a usable shape, so the unit isn't clipped away entirely.
+    pub fn resolve(&self, boundary: &PhysicalBox) -> Option<ResolvedClip> {
+        let to_parent = Ctm::translate(boundary.x, boundary.y);
+        let shapes: Vec<ClipShape> = self.area.iter()
+            .filter_map(|area| area.resolve(&to_parent))
+            .collect();
+        if shapes.is_empty() {
+            log::warn!("Clip without a usable Area is ignored");
+            return None;
+        }
+        Some(ResolvedClip { shapes })
+    }
+}
+
+impl ClipArea {
+    fn resolve(&self, to_parent: &Ctm) -> Option<ClipShape> {
+        let area_transform = Ctm::from_attribute(self.ctm.as_ref()).then(to_parent);
+        if let Some(path) = &self.path {
+            let path_transform = object_transform(path.ctm.as_ref(), path.boundary.x, path.boundary.y);
+            return Some(ClipShape {
+                transform: path_transform.then(&area_transform),
+                commands: path_commands(&path.abbreviated_data),
+                fill_rule: path.rule,
+            });
+        }
+        if let Some(text) = &self.text {
+            let text = text.resolve(None);
+            return Some(ClipShape {
+                transform: text.transform.then(&area_transform),
+                commands: glyph_outlines(&text)?,
+                fill_rule: FillRule::NonZero,
+            });
+        }
+        None
+    }
+}
+
+/// The outlines of the glyphs of a text, in its object space.
+fn glyph_outlines(text: &ResolvedText) -> Option<Vec<PathCommand>> {
+    let font = match RES_FONT_ID_MAP.lock().unwrap().get(text.font.as_str()) {
+        Some(font) => font.clone().take(),
+        None => {
+            log::warn!("font {} of a clip Text not found", text.font);
+            return None;
+        }
+    };
+    // font units are y-up
+    let scale = text.size / font.metrics().units_per_em as f32;
+    let mut commands = Vec::new();
+    for glyph in &text.glyphs {
+        let mut builder = OutlineBuilder::new();
+        let glyph_id = font.glyph_for_char(glyph.c).unwrap_or(0);
+        if font.outline(glyph_id, HintingOptions::None, &mut builder).is_err() {
+            continue;
+        }
+        for contour in builder.into_outline().contours {
+            let points: Vec<(f32, f32)> = contour.positions.iter()
+                .map(|p| (glyph.x + p.x() * scale, glyph.y - p.y() * scale))
+                .collect();
+            let flags = &contour.flags;
+            if points.is_empty() {
+                continue;
+            }
+            commands.push(PathCommand::MoveTo(points[0].0, points[0].1));
+            let mut i = 1;
+            while i < points.len() {
+                let control = flags[i].contains(PointFlags::CONTROL_POINT_0);
+                if control && i + 2 < points.len() && flags[i + 1].contains(PointFlags::CONTROL_POINT_1) {
+                    let ((x1, y1), (x2, y2), (x, y)) = (points[i], points[i + 1], points[i + 2]);
+                    commands.push(PathCommand::CubicTo(x1, y1, x2, y2, x, y));
+                    i += 3;
+                } else if control && i + 1 < points.len() {
+                    let ((x1, y1), (x, y)) = (points[i], points[i + 1]);
+                    commands.push(PathCommand::QuadTo(x1, y1, x, y));
+                    i += 2;
+                } else {
+                    commands.push(PathCommand::LineTo(points[i].0, points[i].1));
+                    i += 1;
+                }
+            }
+            commands.push(PathCommand::Close);
+        }
+    }
+    Some(commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{image_transform, Ctm, Fill, Glyph};
+    use std::sync::Arc;
+    use crate::node_draw::{ResourceScope, MUTEX_IMAGE_RES, MUTEX_RES_DRAW_PARAMS, MUTEX_RGB_IMAGE_RES, MUTEX_STENCIL_IMAGES};
+    use image::{Rgba, RgbaImage};
+    use crate::ofd::{DrawParam, FillRule, ImageObject, PathObject, PhysicalBox, ReflectMethod, TextObject};
+    use crate::shading::Shading;
+
+    #[test]
+    fn test_ctm_then() {
+        // rotate 90 degrees, then move to (10, 0)
+        let t = Ctm::from_v("0 1 -1 0 0 0").unwrap().then(&Ctm::translate(10., 0.));
+        assert_eq!(t.transform_point(1., 0.), (10., 1.));
+        assert_eq!(t.transform_point(0., 1.), (9., 0.));
+    }
+
+    #[test]
+    fn test_malformed_ctm() {
+        assert!(Ctm::from_v("1 0 0").is_none());
+        assert!(Ctm::from_v("1 x 0 1 0 0").is_none());
+        assert!(Ctm::from_v("1 0 0 1 0 0 0").is_none());
+        let t = Ctm::from_attribute(Some(&"1 x 0 1 0 0".to_string()));
+        assert_eq!(t.transform_point(2., 3.), (2., 3.));
+    }
+
+    #[test]
+    fn test_image_transform() {
+        let boundary = PhysicalBox { x: 10., y: 20., width: 30., height: 40. };
+        let t = image_transform(None, &boundary);
+        assert_eq!(t.transform_point(0., 0.), (10., 20.));
+        assert_eq!(t.transform_point(1., 1.), (40., 60.));
+        // mirrored vertically: the top row of the image lands at the bottom
+        let t = image_transform(Some(&"30 0 0 -40 0 40".to_string()), &boundary);
+        assert_eq!(t.transform_point(0., 0.), (10., 60.));
+        // rotated 90 degrees clockwise on the page
+        let t = image_transform(Some(&"0 40 -30 0 30 0".to_string()), &boundary);
+        assert_eq!(t.transform_point(0., 0.), (40., 20.));
+        assert_eq!(t.transform_point(1., 0.), (40., 60.));
+    }
+
+    #[test]
+    fn test_resolve_pattern() {
+        let path_object: PathObject = quick_xml::de::from_str(
+            r#"<PathObject ID="1" Boundary="10 20 30 5" Fill="true" Stroke="false">
+                <FillColor>
+                    <Pattern Width="4" Height="2" XStep="5" ReflectMethod="Row" RelativeTo="Page" CTM="1 0 0 1 1 1">
+                        <CellContent Thumbnail="3"><PathObject ID="2" Boundary="0 0 4 2"><AbbreviatedData>M 0 0 L 4 2</AbbreviatedData></PathObject></CellContent>
+                    </Pattern>
+                </FillColor>
+                <AbbreviatedData>M 0 0 L 30 5</AbbreviatedData>
+            </PathObject>"#
+        ).unwrap();
+        let pattern = match path_object.resolve(None).fill {
+            Some(Fill::Pattern(pattern)) => pattern,
+            fill => panic!("not a pattern: {:?}", fill),
+        };
+        assert_eq!((pattern.x_step, pattern.y_step), (5., 2.));
+        assert_eq!(pattern.reflect_method, ReflectMethod::Row);
+        // the page origin of the pattern, in the object space
+        assert_eq!(pattern.transform.transform_point(0., 0.), (-9., -19.));
+        // every resolution of the pattern shares its cell, so its tile is rendered once
+        match path_object.clone().resolve(None).fill {
+            Some(Fill::Pattern(again)) => assert!(Arc::ptr_eq(&again.cell, &pattern.cell)),
+            fill => panic!("not a pattern: {:?}", fill),
+        }
+    }
+
+    #[test]
+    fn test_resolve_alpha() {
+        let path_object: PathObject = quick_xml::de::from_str(
+            r#"<PathObject ID="1" Boundary="0 0 10 10" Fill="true" Alpha="128">
+                <FillColor Alpha="128">
+                    <AxialShd StartPoint="0 0" EndPoint="10 0" Extend="3">
+                        <Segment><Color Value="0 0 0"/></Segment>
+                        <Segment><Color Value="0 0 0" Alpha="0"/></Segment>
+                    </AxialShd>
+                </FillColor>
+                <StrokeColor Value="0 0 0" Alpha="64"/>
+                <AbbreviatedData>M 0 0 L 10 10</AbbreviatedData>
+            </PathObject>"#
+        ).unwrap();
+        let path = path_object.resolve(None);
+        assert_eq!(path.alpha, 128);
+        match path.fill {
+            Some(Fill::Shading(Shading::Axial { stops, .. })) => {
+                assert_eq!(stops.iter().map(|s| s.color.a).collect::<Vec<_>>(), vec![128, 0]);
+            }
+            fill => panic!("not an axial shading: {:?}", fill),
+        }
+        match path.stroke {
+            Some(Fill::Color(color)) => assert_eq!(color.a, 64),
+            stroke => panic!("not a colour: {:?}", stroke),
+        }
+    }
+
+    #[test]
+    fn test_resolve_image() {
+        let _scope = ResourceScope::open();
+        for (id, width, height, luma) in [("img", 2, 1, 255), ("mask", 1, 1, 128), ("sub", 4, 2, 255)] {
+            let path = format!("Doc_0/Res/test_resolve_image_{}.png", id);
+            MUTEX_IMAGE_RES.lock().unwrap().insert(format!("test_resolve_image_{}", id), path.clone());
+            MUTEX_RGB_IMAGE_RES.lock().unwrap()
+                .insert(path, RgbaImage::from_pixel(width, height, Rgba([luma, luma, luma, 255])));
+        }
+        let image_object: ImageObject = quick_xml::de::from_str(
+            r#"<ImageObject ID="1" Boundary="0 0 10 10" ResourceID="test_resolve_image_img"
+                ImageMask="test_resolve_image_mask" Substitution="test_resolve_image_sub"/>"#
+        ).unwrap();
+        let image = image_object.resolve(None, false).unwrap();
+        assert_eq!(image.dimensions(), (2, 1));
+        // the mask is stretched over the image
+        assert!(image.pixels().all(|p| p[3] == 128));
+
+        let image = image_object.resolve(None, true).unwrap();
+        assert_eq!(image.dimensions(), (4, 2));
+        assert!(image.pixels().all(|p| p[3] == 128));
+
+        // stencils are painted with the fill colour of the draw param
+        let path = "Doc_0/Res/test_resolve_image_stencil.jb2".to_string();
+        MUTEX_IMAGE_RES.lock().unwrap().insert("test_resolve_image_stencil".to_string(), path.clone());
+        MUTEX_RGB_IMAGE_RES.lock().unwrap().insert(path.clone(), RgbaImage::from_fn(2, 1, |x, _| {
+            Rgba([0, 0, 0, if x == 0 { 255 } else { 0 }])
+        }));
+        MUTEX_STENCIL_IMAGES.lock().unwrap().insert(path, true);
+        let draw_param: DrawParam = quick_xml::de::from_str(
+            r#"<DrawParam ID="test_resolve_image_dp"><FillColor Value="255 0 0"/></DrawParam>"#
+        ).unwrap();
+        MUTEX_RES_DRAW_PARAMS.lock().unwrap().insert("test_resolve_image_dp".to_string(), draw_param);
+        let image_object: ImageObject = quick_xml::de::from_str(
+            r#"<ImageObject ID="2" Boundary="0 0 10 10" ResourceID="test_resolve_image_stencil"/>"#
+        ).unwrap();
+        let image = image_object.resolve(Some(&"test_resolve_image_dp".to_string()), false).unwrap();
+        assert_eq!(image.get_pixel(0, 0), &Rgba([255, 0, 0, 255]));
+        assert_eq!(image.get_pixel(1, 0)[3], 0);
+        let image = image_object.resolve(None, false).unwrap();
+        assert_eq!(image.get_pixel(0, 0), &Rgba([0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn test_resolve_clips() {
+        let image_object: ImageObject = quick_xml::de::from_str(
+            r#"<ImageObject ID="1" ResourceID="2" Boundary="10 20 30 40" CTM="30 0 0 40 0 0">
+                <Clips>
+                    <Clip>
+                        <Area CTM="2 0 0 2 0 0">
+                            <Path Boundary="1 1 5 5" Rule="Even-Odd"><AbbreviatedData>M 0 0 L 5 0 L 5 5 C</AbbreviatedData></Path>
+                        </Area>
+                        <Area><Path Boundary="0 0 1 1"><AbbreviatedData>M 0 0 L 1 1 L 0 1 C</AbbreviatedData></Path></Area>
+                    </Clip>
+                    <Clip>
+                        <Area><Text Boundary="0 0 10 10" Font="no such font" Size="3"><TextCode X="0" Y="3">a</TextCode></Text></Area>
+                    </Clip>
+                </Clips>
+            </ImageObject>"#
+        ).unwrap();
+        assert_eq!(image_object.clips.len(), 2);
+        let clip = image_object.clips[0].resolve(&image_object.boundary).unwrap();
+        assert_eq!(clip.shapes.len(), 2);
+        // the path boundary, then the area CTM, then the image boundary; not the image CTM
+        assert_eq!(clip.shapes[0].transform.transform_point(0., 0.), (12., 22.));
+        assert_eq!(clip.shapes[0].transform.transform_point(5., 5.), (22., 32.));
+        assert_eq!(clip.shapes[0].fill_rule, FillRule::EvenOdd);
+        assert_eq!(clip.shapes[1].transform.transform_point(1., 1.), (11., 21.));
+        // a text clip without its font is ignored rather than clipping everything
+        assert!(image_object.clips[1].resolve(&image_object.boundary).is_none());
+    }
+
+    #[test]
+    fn test_resolve_text_glyphs() {
+        let text_object: TextObject = quick_xml::de::from_str(
+            r#"<TextObject ID="1" Boundary="10 20 30 5" Font="1" Size="3" CTM="2 0 0 2 0 0">
+                <TextCode X="1" Y="2" DeltaX="g 2 3">abc</TextCode>
+            </TextObject>"#
+        ).unwrap();
+        let text = text_object.resolve(None);
+        assert_eq!(text.glyphs, vec![
+            Glyph { c: 'a', x: 1., y: 2. },
+            Glyph { c: 'b', x: 4., y: 2. },
+            Glyph { c: 'c', x: 7., y: 2. },
+        ]);
+        assert_eq!(text.transform.transform_point(1., 2.), (12., 24.));
+        assert!(text.fill.is_some());
+    }
+}
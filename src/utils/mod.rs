@@ -1 +1,39 @@
+/// Resolves an OFD location (`ST_Loc`) referenced from a file in `dir` to its path in
+/// the archive. Absolute locations start at the package root, relative ones at `dir`;
+/// `.` and `..` segments are folded.
+pub fn resolve_loc(dir: &str, loc: &str) -> String {
+    let loc = loc.trim().replace('\\', "/");
+    let mut segments: Vec<&str> = Vec::new();
+    let base = if loc.starts_with('/') { "" } else { dir };
+    for segment in base.split('/').chain(loc.split('/')) {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    segments.join("/")
+}
 
+/// The directory of a path in the archive, empty at the package root.
+pub fn loc_dir(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(dir, _)| dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{loc_dir, resolve_loc};
+
+    #[test]
+    fn test_resolve_loc() {
+        assert_eq!(resolve_loc("Doc_0", "Pages/Page_0/Content.xml"), "Doc_0/Pages/Page_0/Content.xml");
+        assert_eq!(resolve_loc("Doc_0/Pages/Page_0", "/Doc_0/Res/image_1.png"), "Doc_0/Res/image_1.png");
+        assert_eq!(resolve_loc("Doc_0/Pages/Page_0", "../../Res/./image_1.png"), "Doc_0/Res/image_1.png");
+        assert_eq!(resolve_loc("Doc_0", "..\\..\\OFD.xml"), "OFD.xml");
+        assert_eq!(resolve_loc("", " Doc_0/Document.xml "), "Doc_0/Document.xml");
+        assert_eq!(loc_dir("Doc_0/Document.xml"), "Doc_0");
+        assert_eq!(loc_dir("OFD.xml"), "");
+    }
+}